@@ -0,0 +1,258 @@
+//! Synchronous versions of netrun APIs for code without an async runtime.
+//!
+//! Everything runs on an internal multi threaded runtime, so these calls work
+//! from any thread, including FFI callers and workers of other multi threaded
+//! runtimes. They can't be used on a current thread runtime, see
+//! [`block_on`].
+
+use std::{net::SocketAddr, ops::Deref, sync::OnceLock, thread::scope, time::Duration};
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    net::ToSocketAddrs,
+    runtime::{Builder, Handle, Runtime, RuntimeFlavor},
+    task::block_in_place,
+};
+
+use crate::{Decode, Encode, ReceiveError, rest, zmq};
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("netrun-blocking")
+            .enable_all()
+            .build()
+            .expect("Failed to start netrun blocking runtime")
+    })
+}
+
+/// Runs the future to completion on the internal runtime.
+///
+/// When called from a worker of a multi threaded tokio runtime, its other
+/// tasks are moved off the worker and the future is driven from a helper
+/// thread, because blocking a runtime thread directly panics.
+///
+/// # Panics
+///
+/// On a current thread runtime. Its only thread would be parked, so nothing
+/// else on it runs until the future completes, which deadlocks as soon as
+/// the future waits for one of its tasks.
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send, {
+    let Ok(handle) = Handle::try_current() else {
+        return runtime().block_on(future);
+    };
+
+    assert!(
+        handle.runtime_flavor() != RuntimeFlavor::CurrentThread,
+        "netrun blocking calls can't be used on a current thread runtime"
+    );
+
+    block_in_place(|| {
+        scope(|s| {
+            s.spawn(|| runtime().block_on(future))
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err))
+        })
+    })
+}
+
+/// Keeps the value owned by the internal runtime, so tasks it spawns on drop
+/// have a runtime to go to.
+struct Owned<T>(Option<T>);
+
+impl<T> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().expect("Value is already dropped")
+    }
+}
+
+impl<T> Drop for Owned<T> {
+    fn drop(&mut self) {
+        let _runtime = runtime().enter();
+        self.0.take();
+    }
+}
+
+/// Blocking [`crate::Client`].
+pub struct Client<In, Out> {
+    inner: Owned<crate::Client<In, Out>>,
+}
+
+impl<In: Decode, Out: Encode> Client<In, Out> {
+    pub fn connect(addr: impl ToSocketAddrs + Send) -> Result<Self> {
+        Ok(Self {
+            inner: Owned(Some(block_on(crate::Client::connect(addr))?)),
+        })
+    }
+
+    pub fn send(&self, val: impl Into<Out>) -> Result<()> {
+        let val = val.into();
+        block_on(self.inner.send(val))
+    }
+
+    pub fn receive(&self) -> Result<In, ReceiveError> {
+        block_on(self.inner.receive())
+    }
+
+    pub fn receive_timeout(&self, duration: Duration) -> Result<In, ReceiveError> {
+        block_on(self.inner.receive_timeout(duration))
+    }
+
+    pub fn try_receive(&self) -> Result<Option<In>, ReceiveError> {
+        let _runtime = runtime().enter();
+        self.inner.try_receive()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        block_on(self.inner.local_addr())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        block_on(self.inner.peer_addr())
+    }
+}
+
+impl<In, Out> std::fmt::Debug for Client<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+/// Blocking [`zmq::Req`].
+pub struct Req<In: Serialize + 'static, Out: DeserializeOwned + 'static> {
+    inner: Owned<zmq::Req<In, Out>>,
+}
+
+impl<In: Serialize + Send + 'static, Out: DeserializeOwned + Send + 'static> Req<In, Out> {
+    pub fn new(endpoint: &str) -> Result<Self> {
+        Ok(Self {
+            inner: Owned(Some(block_on(zmq::Req::new(endpoint))?)),
+        })
+    }
+
+    pub fn send(&self, input: In) -> Result<Out> {
+        block_on(self.inner.send(input))
+    }
+}
+
+/// Blocking [`rest::get`].
+pub fn get<T: DeserializeOwned + Send>(url: impl ToString) -> Result<T> {
+    let url = url.to_string();
+    block_on(rest::get(url))
+}
+
+/// Blocking [`rest::download`].
+pub fn download(url: impl ToString) -> Result<Vec<u8>> {
+    let url = url.to_string();
+    block_on(rest::download(url))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener},
+        thread::spawn,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        Server, Service,
+        blocking::{self, block_on, runtime},
+        zmq::Rep,
+    };
+
+    #[derive(Clone)]
+    struct Double;
+
+    impl Service<u32, u32> for Double {
+        async fn respond(&self, i: u32) -> Result<u32> {
+            Ok(i * 2)
+        }
+    }
+
+    #[test]
+    fn test_blocking_client() -> Result<()> {
+        let server = block_on(Server::start(57811))?;
+        runtime().spawn(async move { server.serve(Double).await });
+
+        let client = blocking::Client::<u32, u32>::connect((Ipv4Addr::LOCALHOST, 57811))?;
+
+        for i in 0..10 {
+            client.send(i)?;
+            assert_eq!(i * 2, client.receive()?);
+        }
+
+        assert!(client.receive_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(57811, client.peer_addr()?.port());
+
+        spawn(move || {
+            client.send(21u32)?;
+            assert_eq!(42, client.receive()?);
+            anyhow::Ok(())
+        })
+        .join()
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_blocking_inside_runtime() -> Result<()> {
+        let rep = block_on(Rep::<u32, u32>::new("tcp://127.0.0.1:57812"))?;
+        rep.on_receive(|val| val + 1);
+
+        let req = blocking::Req::<u32, u32>::new("tcp://127.0.0.1:57812")?;
+
+        for i in 0..10 {
+            assert_eq!(i + 1, req.send(i)?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "current thread runtime")]
+    async fn test_blocking_on_current_thread() {
+        block_on(async {});
+    }
+
+    #[test]
+    fn test_blocking_get() -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let port = listener.local_addr()?.port();
+
+        spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream?;
+                let mut request = [0u8; 1024];
+                _ = stream.read(&mut request)?;
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\n[1,2,3]")?;
+            }
+            anyhow::Ok(())
+        });
+
+        let url = format!("http://127.0.0.1:{port}");
+
+        assert_eq!(vec![1, 2, 3], blocking::get::<Vec<u32>>(&url)?);
+        assert_eq!(b"[1,2,3]".to_vec(), blocking::download(&url)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow, bail};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connection::{RateLimit, ServerConfig},
+    serde::Encoding,
+};
+
+/// IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+/// A bare address is treated as a single host network. Host bits are
+/// cleared and IPv4-mapped networks like `::ffff:10.0.0.0/104` become IPv4
+/// ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr:   IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        if prefix > max {
+            bail!("Invalid prefix /{prefix} for {addr}");
+        }
+
+        let (addr, prefix) = match addr {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix - 96),
+                None => (addr, prefix),
+            },
+            _ => (addr, prefix),
+        };
+
+        let addr = match addr {
+            IpAddr::V4(net) => IpAddr::V4((u32::from(net) & v4_mask(prefix)).into()),
+            IpAddr::V6(net) => IpAddr::V6((u128::from(net) & v6_mask(prefix)).into()),
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(net) == u32::from(ip) & v4_mask(self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(net) == u128::from(ip) & v6_mask(self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|err| anyhow!("Invalid CIDR {s}: {err}"))?;
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|err| anyhow!("Invalid CIDR {s}: {err}"))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Self::new(addr, prefix)
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Denied(Cidr),
+    NotAllowed,
+    PerIpLimit(usize),
+    ConnectionLimit(usize),
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Denied(cidr) => write!(f, "denied by {cidr}"),
+            Self::NotAllowed => write!(f, "not in allow list"),
+            Self::PerIpLimit(limit) => write!(f, "limit of {limit} connections per IP reached"),
+            Self::ConnectionLimit(limit) => write!(f, "limit of {limit} connections reached"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerMetrics {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub active:   u64,
+    pub limited:  u64,
+}
+
+/// Applies accept filters and keeps count of live connections.
+#[derive(Debug, Default)]
+pub(crate) struct Gate {
+    config:   ServerConfig,
+    active:   Mutex<HashMap<IpAddr, usize>>,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    limited:  AtomicU64,
+}
+
+impl Gate {
+    pub fn new(config: ServerConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            ..Default::default()
+        })
+    }
+
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Admission, Rejection> {
+        let result = self.check(ip);
+
+        match result {
+            Ok(()) => self.accepted.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.rejected.fetch_add(1, Ordering::Relaxed),
+        };
+
+        result.map(|()| Admission {
+            gate: self.clone(),
+            ip,
+        })
+    }
+
+    fn check(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let config = &self.config;
+
+        if let Some(cidr) = config.deny.iter().find(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::Denied(*cidr));
+        }
+
+        if !config.allow.is_empty() && !config.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::NotAllowed);
+        }
+
+        let mut active = self.active.lock();
+
+        if let Some(limit) = config.max_connections
+            && active.values().sum::<usize>() >= limit
+        {
+            return Err(Rejection::ConnectionLimit(limit));
+        }
+
+        let count = active.get(&ip).copied().unwrap_or_default();
+
+        if let Some(limit) = config.max_connections_per_ip
+            && count >= limit
+        {
+            return Err(Rejection::PerIpLimit(limit));
+        }
+
+        active.insert(ip, count + 1);
+
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> ServerMetrics {
+        ServerMetrics {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            active:   self.active.lock().values().sum::<usize>() as u64,
+            limited:  self.limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Slot of an admitted connection. Released when the connection closes.
+pub(crate) struct Admission {
+    gate: Arc<Gate>,
+    ip:   IpAddr,
+}
+
+impl Admission {
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.gate.config.rate_limit
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.gate.config.encoding
+    }
+
+    /// Counts a message which hit the rate limit.
+    pub fn limited(&self) {
+        self.gate.limited.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut active = self.gate.active.lock();
+
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    use crate::Cidr;
+
+    #[test]
+    fn test_cidr() -> Result<()> {
+        let net: Cidr = "192.168.0.0/16".parse()?;
+
+        assert!(net.contains(IpAddr::V4(Ipv4Addr::new(192, 168, 10, 1))));
+        assert!(!net.contains(IpAddr::V4(Ipv4Addr::new(192, 169, 0, 1))));
+        assert!(net.contains(IpAddr::V6(Ipv4Addr::new(192, 168, 0, 1).to_ipv6_mapped())));
+        assert!(!net.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        let host: Cidr = "127.0.0.1".parse()?;
+        assert_eq!("127.0.0.1/32", host.to_string());
+        assert!(host.contains(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!host.contains(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))));
+
+        let all: Cidr = "0.0.0.0/0".parse()?;
+        assert!(all.contains(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+
+        let v6: Cidr = "fd00::/8".parse()?;
+        assert!(v6.contains("fd12::1".parse()?));
+        assert!(!v6.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        let unmasked: Cidr = "10.0.0.1/8".parse()?;
+        assert_eq!("10.0.0.0/8", unmasked.to_string());
+        assert!(unmasked.contains(IpAddr::V4(Ipv4Addr::new(10, 200, 0, 1))));
+
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse()?;
+        assert_eq!(unmasked, mapped);
+        assert!(mapped.contains(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(!mapped.contains(IpAddr::V4(Ipv4Addr::new(11, 0, 0, 1))));
+
+        let mapped_host: Cidr = "::ffff:127.0.0.1".parse()?;
+        assert_eq!(host, mapped_host);
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense/8".parse::<Cidr>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    any::type_name,
+    hash::{Hash, Hasher},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, trace};
+use parking_lot::Mutex as SyncMutex;
+use tokio::{select, spawn, sync::Mutex, time::timeout};
+use tokio_util::sync::CancellationToken;
+use twox_hash::XxHash64;
+
+use crate::connection::{Client, Decode, Encode};
+
+type Picked<'a, In, Out> = (&'a Endpoint<In, Out>, Arc<Client<In, Out>>);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    /// Requests with the same key go to the same endpoint while it is
+    /// healthy. Requests without a key fall back to round robin.
+    ConsistentHash,
+}
+
+struct Endpoint<In, Out> {
+    address:     String,
+    client:      SyncMutex<Option<Arc<Client<In, Out>>>>,
+    exchange:    Mutex<()>,
+    outstanding: AtomicUsize,
+}
+
+impl<In: Decode, Out: Encode> Endpoint<In, Out> {
+    fn new(address: String) -> Self {
+        Self {
+            address,
+            client: SyncMutex::new(None),
+            exchange: Mutex::new(()),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    fn connected(&self) -> Option<Arc<Client<In, Out>>> {
+        self.client.lock().as_ref().filter(|client| !client.is_closed()).cloned()
+    }
+
+    async fn reconnect(&self) {
+        match timeout(CONNECT_TIMEOUT, Client::connect(self.address.as_str())).await {
+            Ok(Ok(client)) => {
+                debug!("Endpoint {} is healthy", self.address);
+                *self.client.lock() = Some(Arc::new(client));
+            }
+            Ok(Err(err)) => trace!("Endpoint {} is down: {err}", self.address),
+            Err(_) => trace!("Endpoint {} connection timeout", self.address),
+        }
+    }
+
+    fn is_current(&self, client: &Arc<Client<In, Out>>) -> bool {
+        self.client.lock().as_ref().is_some_and(|current| Arc::ptr_eq(current, client))
+    }
+
+    fn remove(&self, client: &Arc<Client<In, Out>>) {
+        let mut current = self.client.lock();
+
+        if current.as_ref().is_some_and(|current| Arc::ptr_eq(current, client)) {
+            debug!("Endpoint {} removed", self.address);
+            *current = None;
+        }
+    }
+}
+
+/// Counts a request as outstanding until it's dropped.
+struct Outstanding<'a>(&'a AtomicUsize);
+
+impl<'a> Outstanding<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Removes the connection unless the exchange finished. A reply to a
+/// cancelled or failed request may still arrive, and would be taken as the
+/// reply to the next one.
+struct Exchange<'a, In: Decode, Out: Encode> {
+    endpoint: &'a Endpoint<In, Out>,
+    client:   &'a Arc<Client<In, Out>>,
+    finished: bool,
+}
+
+impl<In: Decode, Out: Encode> Drop for Exchange<'_, In, Out> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.endpoint.remove(self.client);
+        }
+    }
+}
+
+/// Request/response client spread over several replicas of the same service.
+///
+/// Endpoints with broken connections are skipped and reconnected in
+/// background. So are endpoints which failed a request or didn't answer it
+/// in time, see [`BalancedClient::set_request_timeout`].
+pub struct BalancedClient<In, Out> {
+    endpoints: Arc<Vec<Endpoint<In, Out>>>,
+    strategy:  Strategy,
+    next:      AtomicUsize,
+    interval:  Arc<AtomicU64>,
+    timeout:   AtomicU64,
+    cancel:    CancellationToken,
+}
+
+impl<In: Decode, Out: Encode> BalancedClient<In, Out> {
+    pub async fn connect(
+        addresses: impl IntoIterator<Item = impl ToString>,
+        strategy: Strategy,
+    ) -> Result<Self> {
+        let endpoints: Arc<Vec<_>> = Arc::new(
+            addresses
+                .into_iter()
+                .map(|address| Endpoint::new(address.to_string()))
+                .collect(),
+        );
+
+        for endpoint in endpoints.iter() {
+            endpoint.reconnect().await;
+        }
+
+        if !endpoints.iter().any(|endpoint| endpoint.connected().is_some()) {
+            bail!("None of {} endpoints are reachable", endpoints.len());
+        }
+
+        let cancel = CancellationToken::new();
+        let interval = Arc::new(AtomicU64::new(DEFAULT_HEALTH_INTERVAL.as_millis().try_into()?));
+
+        let ends = endpoints.clone();
+        let cn = cancel.clone();
+        let int = interval.clone();
+
+        spawn(async move {
+            loop {
+                select! {
+                    () = cn.cancelled() => break,
+                    () = tokio::time::sleep(Duration::from_millis(int.load(Ordering::Relaxed))) => (),
+                }
+
+                for endpoint in ends.iter() {
+                    if endpoint.connected().is_none() {
+                        endpoint.reconnect().await;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            endpoints,
+            strategy,
+            next: AtomicUsize::new(0),
+            interval,
+            timeout: AtomicU64::new(DEFAULT_REQUEST_TIMEOUT.as_millis().try_into()?),
+            cancel,
+        })
+    }
+
+    pub async fn request(&self, val: impl Into<Out>) -> Result<In> {
+        self.dispatch(None, val.into()).await
+    }
+
+    /// Same as [`BalancedClient::request`] but routed by key when using
+    /// [`Strategy::ConsistentHash`].
+    pub async fn request_by_key(&self, key: impl Hash, val: impl Into<Out>) -> Result<In> {
+        let mut hasher = XxHash64::with_seed(0);
+        key.hash(&mut hasher);
+        self.dispatch(Some(hasher.finish()), val.into()).await
+    }
+
+    /// How often endpoints that are down are reconnected.
+    pub fn set_health_interval(&self, interval: Duration) {
+        self.interval.store(
+            interval.as_millis().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// How long a request may take before the endpoint is considered hung.
+    /// 30 seconds by default.
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        self.timeout.store(
+            timeout.as_millis().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn healthy(&self) -> usize {
+        self.endpoints.iter().filter(|endpoint| endpoint.connected().is_some()).count()
+    }
+
+    async fn dispatch(&self, key: Option<u64>, val: Out) -> Result<In> {
+        let limit = Duration::from_millis(self.timeout.load(Ordering::Relaxed));
+
+        loop {
+            let (endpoint, client) = self.pick(key).ok_or(anyhow!("No healthy endpoints"))?;
+
+            let _outstanding = Outstanding::new(&endpoint.outstanding);
+            let _lock = endpoint.exchange.lock().await;
+
+            // Removed while waiting for the previous exchange.
+            if !endpoint.is_current(&client) {
+                continue;
+            }
+
+            let mut exchange = Exchange {
+                endpoint,
+                client: &client,
+                finished: false,
+            };
+
+            let result = timeout(limit, async {
+                client.send(val).await?;
+                Ok(client.receive().await?)
+            })
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow!(
+                    "Endpoint {} didn't respond in {limit:?}",
+                    endpoint.address
+                ))
+            });
+
+            exchange.finished = result.is_ok();
+
+            return result;
+        }
+    }
+
+    fn pick(&self, key: Option<u64>) -> Option<Picked<'_, In, Out>> {
+        let healthy: Vec<_> = self
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.connected().map(|client| (endpoint, client)))
+            .collect();
+
+        if healthy.is_empty() {
+            return None;
+        }
+
+        let len = healthy.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+
+        match (self.strategy, key) {
+            (Strategy::ConsistentHash, Some(key)) => healthy
+                .into_iter()
+                .max_by_key(|(endpoint, _)| XxHash64::oneshot(key, endpoint.address.as_bytes())),
+            (Strategy::LeastOutstanding, _) => healthy
+                .into_iter()
+                .enumerate()
+                .min_by_key(|(i, (endpoint, _))| {
+                    (
+                        endpoint.outstanding.load(Ordering::Relaxed),
+                        (i + len - start) % len,
+                    )
+                })
+                .map(|(_, picked)| picked),
+            _ => healthy.into_iter().nth(start),
+        }
+    }
+}
+
+impl<In, Out> Drop for BalancedClient<In, Out> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<In, Out> std::fmt::Debug for BalancedClient<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = type_name::<In>();
+        let o = type_name::<Out>();
+
+        let addresses: Vec<_> = self.endpoints.iter().map(|endpoint| &endpoint.address).collect();
+
+        f.debug_struct(&format!("BalancedClient<{i}, {o}>"))
+            .field("strategy", &self.strategy)
+            .field("endpoints", &addresses)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use anyhow::{Result, bail};
+    use hreads::log_spawn;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::time::{sleep, timeout};
+
+    use crate::{BalancedClient, Retry, Server, Service, Strategy, tests::relay};
+
+    #[derive(Clone)]
+    struct PortService(u16);
+
+    impl Service<(), u16> for PortService {
+        fn respond(&self, (): ()) -> impl Future<Output = Result<u16>> + Send {
+            let port = self.0;
+            async move { Ok(port) }
+        }
+    }
+
+    /// Echoes the request after the given number of milliseconds.
+    #[derive(Clone)]
+    struct SlowEcho;
+
+    impl Service<u64, u64> for SlowEcho {
+        async fn respond(&self, delay: u64) -> Result<u64> {
+            sleep(Duration::from_millis(delay)).await;
+            Ok(delay)
+        }
+    }
+
+    async fn replicas(ports: &[u16]) -> Result<()> {
+        for &port in ports {
+            let server = Server::start(port).await?;
+            log_spawn(async move { server.serve(PortService(port)).await });
+        }
+        sleep(Duration::from_millis(50)).await;
+        Ok(())
+    }
+
+    async fn distribution(client: &BalancedClient<u16, ()>, requests: usize) -> Result<BTreeMap<u16, usize>> {
+        let mut hits = BTreeMap::new();
+        for _ in 0..requests {
+            *hits.entry(client.request(()).await?).or_default() += 1;
+        }
+        Ok(hits)
+    }
+
+    #[test(tokio::test)]
+    async fn test_round_robin_and_recovery() -> Result<()> {
+        replicas(&[57796, 57797, 57798]).await?;
+
+        let _relay_1 = relay(57799, 57796).await?;
+        let relay_2 = relay(57800, 57797).await?;
+        let _relay_3 = relay(57801, 57798).await?;
+
+        let client = BalancedClient::<u16, ()>::connect(
+            ["127.0.0.1:57799", "127.0.0.1:57800", "127.0.0.1:57801"],
+            Strategy::RoundRobin,
+        )
+        .await?;
+
+        client.set_health_interval(Duration::from_millis(50));
+
+        assert_eq!(
+            BTreeMap::from([(57796, 2), (57797, 2), (57798, 2)]),
+            distribution(&client, 6).await?
+        );
+
+        relay_2.abort();
+        _ = relay_2.await;
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(2, client.healthy());
+        assert_eq!(
+            BTreeMap::from([(57796, 2), (57798, 2)]),
+            distribution(&client, 4).await?
+        );
+
+        let _relay_2 = relay(57800, 57797).await?;
+
+        Retry::times(20)
+            .run(|| async {
+                sleep(Duration::from_millis(50)).await;
+                if client.healthy() == 3 {
+                    Ok(())
+                } else {
+                    bail!("Endpoint didn't recover")
+                }
+            })
+            .await?;
+
+        assert_eq!(3, distribution(&client, 3).await?.len());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_consistent_hash() -> Result<()> {
+        replicas(&[57802, 57803, 57804]).await?;
+
+        let client = BalancedClient::<u16, ()>::connect(
+            ["127.0.0.1:57802", "127.0.0.1:57803", "127.0.0.1:57804"],
+            Strategy::ConsistentHash,
+        )
+        .await?;
+
+        let mut ports = BTreeMap::new();
+
+        for user in 0..30 {
+            let port = client.request_by_key(user, ()).await?;
+            assert_eq!(port, client.request_by_key(user, ()).await?);
+            *ports.entry(port).or_insert(0) += 1;
+        }
+
+        assert_eq!(3, ports.len());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_least_outstanding() -> Result<()> {
+        replicas(&[57805, 57806]).await?;
+
+        let client = BalancedClient::<u16, ()>::connect(
+            ["127.0.0.1:57805", "127.0.0.1:57806"],
+            Strategy::LeastOutstanding,
+        )
+        .await?;
+
+        assert_eq!(2, distribution(&client, 10).await?.len());
+
+        assert!(
+            BalancedClient::<u16, ()>::connect(["127.0.0.1:57807"], Strategy::RoundRobin)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_cancelled_and_timed_out_requests() -> Result<()> {
+        let server = Server::start(57837).await?;
+        log_spawn(async move { server.serve(SlowEcho).await });
+        sleep(Duration::from_millis(50)).await;
+
+        let client = BalancedClient::<u64, u64>::connect(["127.0.0.1:57837"], Strategy::RoundRobin).await?;
+        client.set_health_interval(Duration::from_millis(50));
+
+        let recovered = || {
+            Retry::times(20).run(|| async {
+                sleep(Duration::from_millis(50)).await;
+                if client.healthy() == 1 {
+                    Ok(())
+                } else {
+                    bail!("Endpoint didn't recover")
+                }
+            })
+        };
+
+        assert!(timeout(Duration::from_millis(50), client.request(200_u64)).await.is_err());
+        assert_eq!(0, client.healthy());
+        recovered().await?;
+
+        // The late reply to the cancelled request must not be taken for this one.
+        assert_eq!(1, client.request(1_u64).await?);
+
+        client.set_request_timeout(Duration::from_millis(50));
+        assert!(client.request(200_u64).await.is_err());
+        assert_eq!(0, client.healthy());
+        recovered().await?;
+
+        assert_eq!(1, client.request(1_u64).await?);
+
+        Ok(())
+    }
+}
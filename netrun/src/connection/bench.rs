@@ -0,0 +1,406 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::{net::lookup_host, task::JoinSet};
+
+use crate::{
+    connection::{Client, Decode, Encode, Service},
+    serde::{Codec, Encoding},
+};
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+const ACK: u8 = 2;
+
+const PATTERN: &[u8] = b"netrun benchmark payload 0123456789 ";
+
+/// Bytes of field data in a [`BenchRecord`].
+const RECORD_SIZE: usize = 64;
+
+/// How benchmark payloads are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BenchMode {
+    /// Sent as is, like [`Bytes`](crate::Bytes).
+    Raw,
+    /// A list of [`BenchRecord`]s going through the [`Encoding`] of the
+    /// connection, like any serde message.
+    Compressed,
+}
+
+/// Structured payload of [`BenchMode::Compressed`], shaped like a typical
+/// application message. Holds 64 bytes of field data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BenchRecord {
+    pub id:     u64,
+    pub name:   String,
+    pub active: bool,
+    pub score:  u32,
+    pub tags:   Vec<String>,
+}
+
+impl BenchRecord {
+    fn new(i: u64) -> Self {
+        Self {
+            id:     i,
+            name:   format!("record {i:012}"),
+            active: !i.is_multiple_of(3),
+            score:  u32::try_from(i * 7919 % 100_000).expect("Below 100 000"),
+            tags:   (0..4).map(|tag| format!("tag-{:04}", (i + tag) % 10_000)).collect(),
+        }
+    }
+}
+
+/// Message exchanged by [`Benchmark`] and [`BenchService`].
+///
+/// The kind is stored in the last byte so raw payloads aren't copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BenchMessage {
+    Raw(Vec<u8>),
+    Compressed(Vec<BenchRecord>),
+    Ack,
+}
+
+impl BenchMessage {
+    /// Compressed payloads are rounded up to whole records.
+    fn payload(mode: BenchMode, size: usize) -> Self {
+        match mode {
+            BenchMode::Raw => Self::Raw(PATTERN.iter().copied().cycle().take(size).collect()),
+            BenchMode::Compressed => Self::Compressed(
+                (0..size.div_ceil(RECORD_SIZE).max(1) as u64).map(BenchRecord::new).collect(),
+            ),
+        }
+    }
+
+    /// Payload bytes before encoding.
+    fn size(&self) -> usize {
+        match self {
+            Self::Raw(data) => data.len(),
+            Self::Compressed(records) => records.len() * RECORD_SIZE,
+            Self::Ack => 0,
+        }
+    }
+}
+
+impl Encode for BenchMessage {
+    fn encode_with(self, encoding: Encoding) -> Result<Vec<u8>> {
+        let (mut data, kind) = match self {
+            Self::Raw(data) => (data, RAW),
+            Self::Compressed(records) => (Codec::encode(&encoding, &records)?, COMPRESSED),
+            Self::Ack => (vec![], ACK),
+        };
+        data.push(kind);
+        Ok(data)
+    }
+}
+
+impl Decode for BenchMessage {
+    fn decode_with(mut data: Vec<u8>, encoding: Encoding) -> Result<Self> {
+        Ok(match data.pop() {
+            Some(RAW) => Self::Raw(data),
+            Some(COMPRESSED) => Self::Compressed(Codec::decode(&encoding, &data)?),
+            Some(ACK) => Self::Ack,
+            Some(kind) => bail!("Unknown benchmark message: {kind}"),
+            None => bail!("Empty benchmark message"),
+        })
+    }
+}
+
+/// Acknowledges every payload after decoding it.
+///
+/// ```ignore
+/// Server::<BenchMessage, BenchMessage>::start(port).await?.serve(BenchService).await?;
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BenchService;
+
+impl Service<BenchMessage, BenchMessage> for BenchService {
+    async fn respond(&self, message: BenchMessage) -> Result<BenchMessage> {
+        match message {
+            BenchMessage::Raw(_) | BenchMessage::Compressed(_) => Ok(BenchMessage::Ack),
+            BenchMessage::Ack => Err(anyhow!("Benchmark service received an ack")),
+        }
+    }
+}
+
+/// Measurements of one mode and payload size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub mode:             BenchMode,
+    pub payload_size:     usize,
+    pub concurrency:      usize,
+    pub messages:         u64,
+    /// Payload bytes before encoding. Compressed payloads are rounded up to
+    /// whole records.
+    pub bytes:            u64,
+    /// Bytes after encoding, as sent over the connection.
+    pub wire_bytes:       u64,
+    pub elapsed:          Duration,
+    pub messages_per_sec: f64,
+    pub bytes_per_sec:    f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub address: SocketAddr,
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    pub fn result(&self, mode: BenchMode, payload_size: usize) -> Option<&BenchResult> {
+        self.results
+            .iter()
+            .find(|result| result.mode == mode && result.payload_size == payload_size)
+    }
+}
+
+impl Display for BenchReport {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Benchmark against {}", self.address)?;
+        writeln!(
+            f,
+            "{:<10} {:>10} {:>6} {:>12} {:>12} {:>8}",
+            "mode", "payload", "conn", "msg/s", "MB/s", "ratio"
+        )?;
+
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<10} {:>10} {:>6} {:>12.0} {:>12.2} {:>8.2}",
+                format!("{:?}", result.mode),
+                result.payload_size,
+                result.concurrency,
+                result.messages_per_sec,
+                result.bytes_per_sec / 1_000_000.0,
+                result.wire_bytes as f64 / result.bytes.max(1) as f64,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Client side of an iperf like benchmark against a [`BenchService`].
+///
+/// For each mode and payload size it opens `concurrency` connections, each
+/// sending payloads and waiting for their acks for the whole duration.
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    payload_sizes: Vec<usize>,
+    modes:         Vec<BenchMode>,
+    concurrency:   usize,
+    duration:      Duration,
+    encoding:      Encoding,
+}
+
+impl Default for Benchmark {
+    fn default() -> Self {
+        Self {
+            payload_sizes: vec![64, 1024, 64 * 1024],
+            modes:         vec![BenchMode::Raw, BenchMode::Compressed],
+            concurrency:   4,
+            duration:      Duration::from_secs(1),
+            encoding:      Encoding::default(),
+        }
+    }
+}
+
+impl Benchmark {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn payload_sizes(mut self, sizes: impl Into<Vec<usize>>) -> Self {
+        self.payload_sizes = sizes.into();
+        self
+    }
+
+    pub fn modes(mut self, modes: impl Into<Vec<BenchMode>>) -> Self {
+        self.modes = modes.into();
+        self
+    }
+
+    /// Number of parallel connections. [`Benchmark::run`] fails for 0.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Duration of each measurement.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Encoding of the compressed mode.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub async fn run(&self, address: impl ToString) -> Result<BenchReport> {
+        if self.concurrency == 0 {
+            bail!("Benchmark concurrency can't be 0");
+        }
+
+        let address = address.to_string();
+        let address = lookup_host(&address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to resolve {address}"))?;
+
+        let mut results = vec![];
+
+        for &mode in &self.modes {
+            for &size in &self.payload_sizes {
+                results.push(self.measure(address, mode, size).await?);
+            }
+        }
+
+        Ok(BenchReport { address, results })
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    async fn measure(&self, address: SocketAddr, mode: BenchMode, size: usize) -> Result<BenchResult> {
+        let payload = BenchMessage::payload(mode, size);
+        let payload_size = payload.size() as u64;
+        let wire_size = payload.clone().encode_with(self.encoding)?.len() as u64;
+
+        let mut clients = Vec::with_capacity(self.concurrency);
+        for _ in 0..self.concurrency {
+            let client = Client::<BenchMessage, BenchMessage>::connect(address).await?;
+            client.set_encoding(self.encoding);
+            clients.push(client);
+        }
+
+        let start = Instant::now();
+        let deadline = start + self.duration;
+
+        let mut workers = JoinSet::new();
+
+        for client in clients {
+            let payload = payload.clone();
+
+            workers.spawn(async move {
+                let mut messages = 0u64;
+
+                while Instant::now() < deadline {
+                    client.send(payload.clone()).await?;
+
+                    if client.receive().await? != BenchMessage::Ack {
+                        bail!("Benchmark service didn't acknowledge the payload");
+                    }
+
+                    messages += 1;
+                }
+
+                anyhow::Ok(messages)
+            });
+        }
+
+        let mut messages = 0;
+        while let Some(worker) = workers.join_next().await {
+            messages += worker??;
+        }
+
+        let elapsed = start.elapsed();
+        let bytes = messages * payload_size;
+
+        Ok(BenchResult {
+            mode,
+            payload_size: size,
+            concurrency: self.concurrency,
+            messages,
+            bytes,
+            wire_bytes: messages * wire_size,
+            elapsed,
+            messages_per_sec: messages as f64 / elapsed.as_secs_f64(),
+            bytes_per_sec: bytes as f64 / elapsed.as_secs_f64(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use hreads::log_spawn;
+    use log::debug;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use crate::{BenchMessage, BenchMode, BenchReport, BenchService, Benchmark, Decode, Encode, Server};
+
+    #[test]
+    fn test_bench_message() -> Result<()> {
+        for message in [
+            BenchMessage::Raw(vec![1, 2, 3]),
+            BenchMessage::Raw(vec![]),
+            BenchMessage::payload(BenchMode::Compressed, 100),
+            BenchMessage::Ack,
+        ] {
+            assert_eq!(message, BenchMessage::decode(message.clone().encode()?)?);
+        }
+
+        assert_eq!(4, BenchMessage::Raw(vec![1, 2, 3]).encode()?.len());
+        assert!(BenchMessage::decode(vec![]).is_err());
+
+        assert_eq!(128, BenchMessage::payload(BenchMode::Compressed, 100).size());
+        assert_eq!(64, BenchMessage::payload(BenchMode::Compressed, 0).size());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_benchmark() -> Result<()> {
+        let server = Server::<BenchMessage, BenchMessage>::start(57826).await?;
+
+        log_spawn(async move {
+            server.serve(BenchService).await?;
+            Ok(())
+        });
+
+        let report = Benchmark::new()
+            .payload_sizes([16, 4096])
+            .concurrency(2)
+            .duration(Duration::from_millis(100))
+            .run("127.0.0.1:57826")
+            .await?;
+
+        assert_eq!(4, report.results.len());
+
+        for result in &report.results {
+            assert!(result.messages > 0);
+            assert_eq!(2, result.concurrency);
+            assert!(result.messages * result.payload_size as u64 <= result.bytes);
+            assert!(result.messages_per_sec > 0.0);
+        }
+
+        let raw = report.result(BenchMode::Raw, 4096).unwrap();
+        let compressed = report.result(BenchMode::Compressed, 4096).unwrap();
+
+        assert_eq!(raw.messages * 4097, raw.wire_bytes);
+        assert!(compressed.wire_bytes < compressed.bytes);
+
+        let parsed: BenchReport = serde_json::from_str(&serde_json::to_string(&report)?)?;
+        assert_eq!(report.address, parsed.address);
+        for (result, parsed) in report.results.iter().zip(&parsed.results) {
+            assert_eq!(
+                (result.mode, result.messages, result.elapsed),
+                (parsed.mode, parsed.messages, parsed.elapsed)
+            );
+        }
+
+        debug!("{report}");
+
+        assert!(Benchmark::new().concurrency(0).run("127.0.0.1:57826").await.is_err());
+
+        Ok(())
+    }
+}
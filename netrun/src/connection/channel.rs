@@ -0,0 +1,216 @@
+use std::{
+    any::type_name,
+    marker::PhantomData,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use log::error;
+use parking_lot::Mutex;
+use tokio::{
+    pin,
+    runtime::Handle,
+    sync::{Notify, Semaphore, mpsc::error::TryRecvError},
+    time::timeout,
+};
+
+use crate::{
+    connection::{
+        Decode, Encode, Priority, ReceiveError,
+        frame::Frame,
+        link::{Inbox, Incoming, Item, Link, WINDOW},
+    },
+    serde::{MAX_STREAM_SENDERS, ReplayWindows},
+};
+
+/// Typed logical stream multiplexed over a single connection.
+///
+/// Every channel has its own credit window, so a slow consumer on one channel
+/// doesn't stall the others.
+pub struct Channel<In, Out> {
+    id:       u32,
+    name:     String,
+    link:     Arc<Link>,
+    inbox:    Mutex<Inbox>,
+    arrived:  Arc<Notify>,
+    credits:  [Arc<Semaphore>; 2],
+    consumed: [AtomicU32; 2],
+    /// Each lane is received in the order it was sent, so encrypted
+    /// messages are checked for replays per lane.
+    replays:  [Arc<ReplayWindows>; 2],
+    _p:       PhantomData<Mutex<(In, Out)>>,
+}
+
+impl<In: Decode, Out: Encode> Channel<In, Out> {
+    pub(crate) fn new(link: Arc<Link>, name: impl ToString, incoming: Incoming) -> Self {
+        Self {
+            id: incoming.id,
+            name: name.to_string(),
+            link,
+            inbox: Mutex::new(incoming.inbox),
+            arrived: incoming.arrived,
+            credits: incoming.credits,
+            consumed: [AtomicU32::new(0), AtomicU32::new(0)],
+            replays: [(); 2].map(|()| Arc::new(ReplayWindows::new(MAX_STREAM_SENDERS))),
+            _p: PhantomData,
+        }
+    }
+
+    pub async fn send(&self, val: impl Into<Out>) -> Result<()> {
+        self.send_with_priority(val, Priority::Normal).await
+    }
+
+    /// High priority messages overtake queued normal ones on both sides. Each
+    /// lane has its own credit window.
+    pub async fn send_with_priority(&self, val: impl Into<Out>, priority: Priority) -> Result<()> {
+        let data = self.link.encode(val.into())?;
+
+        let credit = self.credits[priority.index()]
+            .acquire()
+            .await
+            .map_err(|_| anyhow!("Sending to closed channel: {}", self.name))?;
+
+        // A send cancelled before the frame is queued keeps its credit.
+        self.link
+            .send_then(Frame::data(self.id, data).with_priority(priority), || {
+                credit.forget();
+            })
+            .await
+    }
+
+    /// Waits for the next message.
+    ///
+    /// Cancel safe: if the future is dropped before completion, e.g. in
+    /// `select!`, no message is lost.
+    pub async fn receive(&self) -> Result<In, ReceiveError> {
+        Ok(self.receive_with_priority().await?.0)
+    }
+
+    /// Same as [`Channel::receive`] but also tells which lane the message
+    /// came from.
+    pub async fn receive_with_priority(&self) -> Result<(In, Priority), ReceiveError> {
+        loop {
+            let arrived = self.arrived.notified();
+            pin!(arrived);
+            arrived.as_mut().enable();
+
+            if let Some((priority, data)) = self.take()? {
+                return Ok((self.decode(data?, priority)?, priority));
+            }
+
+            arrived.await;
+        }
+    }
+
+    /// Same as [`Channel::receive`] but fails with [`ReceiveError::Timeout`]
+    /// if nothing arrives in time.
+    pub async fn receive_timeout(&self, duration: Duration) -> Result<In, ReceiveError> {
+        timeout(duration, self.receive())
+            .await
+            .map_err(|_| ReceiveError::Timeout(duration))?
+    }
+
+    /// Returns a message if one is already waiting, even while another task
+    /// is receiving from this channel.
+    pub fn try_receive(&self) -> Result<Option<In>, ReceiveError> {
+        match self.take()? {
+            Some((priority, data)) => self.decode(data?, priority).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Takes the next message out of the inbox without waiting.
+    fn take(&self) -> Result<Option<(Priority, Item)>, ReceiveError> {
+        let (priority, item) = match self.inbox.lock().try_recv() {
+            Ok(item) => item,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => return Err(ReceiveError::Closed),
+        };
+
+        self.grant_credit(priority);
+
+        Ok(Some((priority, item)))
+    }
+
+    fn decode(&self, data: Vec<u8>, priority: Priority) -> Result<In, ReceiveError> {
+        let encoding = self.link.encoding();
+        self.replays[priority.index()]
+            .check(|| In::decode_with(data, encoding))
+            .map_err(ReceiveError::Decode)
+    }
+
+    /// Credit is returned in batches to avoid a frame per received message.
+    /// The frame is sent from a separate task so receiving never awaits
+    /// after a message was taken from the inbox.
+    fn grant_credit(&self, priority: Priority) {
+        let counter = &self.consumed[priority.index()];
+        let consumed = counter.load(Ordering::Relaxed) + 1;
+
+        if consumed < WINDOW / 2 {
+            counter.store(consumed, Ordering::Relaxed);
+            return;
+        }
+
+        counter.store(0, Ordering::Relaxed);
+
+        let Ok(handle) = Handle::try_current() else {
+            error!("Failed to grant credit on channel {}: no runtime", self.name);
+            return;
+        };
+
+        let link = self.link.clone();
+        let id = self.id;
+
+        handle.spawn(async move {
+            _ = link
+                .send(Frame::credit(id, consumed).with_priority(priority))
+                .await
+                .inspect_err(|e| error!("Failed to grant credit on channel {id}: {e}"));
+        });
+    }
+}
+
+impl<In, Out> Drop for Channel<In, Out> {
+    fn drop(&mut self) {
+        self.link.unregister(self.id);
+
+        if self.id == 0 || self.link.is_closed() {
+            return;
+        }
+
+        let Ok(handle) = Handle::try_current() else {
+            return;
+        };
+
+        let link = self.link.clone();
+        let id = self.id;
+
+        handle.spawn(async move {
+            _ = link
+                .send(Frame::close(id))
+                .await
+                .inspect_err(|e| error!("Failed to close channel {id}: {e}"));
+        });
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<In, Out> std::fmt::Debug for Channel<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = type_name::<In>();
+        let o = type_name::<Out>();
+
+        f.debug_struct(&format!("Channel<{i}, {o}>"))
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .finish()
+    }
+}
@@ -1,108 +1,208 @@
 use core::net::SocketAddr;
-use std::{any::type_name, marker::PhantomData};
+use std::{any::type_name, sync::Arc, time::Duration};
 
 use anyhow::{Result, anyhow};
-use log::{debug, error};
-use serde::{Serialize, de::DeserializeOwned};
+use log::debug;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs, tcp::OwnedWriteHalf},
-    select, spawn,
-    sync::{
-        Mutex, RwLock,
-        mpsc::{Receiver, Sender, channel},
-    },
+    net::{TcpStream, ToSocketAddrs},
+    time::sleep,
 };
-use tokio_util::sync::CancellationToken;
 
 use crate::{
     System,
-    connection::BUFFER_SIZE,
-    serde::{deserialize, serialize},
+    connection::{
+        Channel, Decode, Encode, Heartbeat, LatencyStats, PingSample, Priority, Proxy, ReceiveError,
+        accept::Admission, link::Link,
+    },
+    serde::{CompressionStats, Encoding, SchemaInfo, registered_schemas},
 };
 
+const SCHEMA_CHANNEL: &str = "netrun.schemas";
+
+/// Side of a connection. Each side numbers the channels it opens with its
+/// own parity, so the two peers have to take different roles.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The connecting side.
+    #[default]
+    Initiator,
+    /// The accepting side, like connections of a [`Server`](crate::Server).
+    Acceptor,
+}
+
+impl Role {
+    /// Channel ids are split by parity so both peers can open channels
+    /// without coordination.
+    pub(crate) const fn first_channel(self) -> u32 {
+        match self {
+            Self::Initiator => 1,
+            Self::Acceptor => 2,
+        }
+    }
+}
+
 pub struct Client<In, Out> {
-    write:    RwLock<OwnedWriteHalf>,
-    receiver: Mutex<Receiver<Result<In>>>,
-    cancel:   CancellationToken,
-    address:  SocketAddr,
-    id:       String,
-    _p:       PhantomData<Mutex<Out>>,
+    main:    Channel<In, Out>,
+    link:    Arc<Link>,
+    address: SocketAddr,
+    id:      String,
 }
 
-impl<In: DeserializeOwned + Send + 'static, Out: Serialize> Client<In, Out> {
+impl<In: Decode, Out: Encode> Client<In, Out> {
     pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
         Ok(Self::from_stream(TcpStream::connect(addr).await?))
     }
 
+    /// Connects to `addr` in `host:port` form through the proxy.
+    pub async fn connect_via(addr: impl ToString, proxy: &Proxy) -> Result<Self> {
+        Ok(Self::from_stream(proxy.connect(&addr.to_string()).await?))
+    }
+
+    /// Takes the [`Role::Initiator`] side, the peer has to be a
+    /// [`Server`](crate::Server) or use [`Client::from_stream_with_role`]
+    /// with [`Role::Acceptor`].
     pub fn from_stream(stream: TcpStream) -> Self {
-        let addr = stream.local_addr().expect("Failed to get stream local_addr");
+        Self::from_stream_with_role(stream, Role::Initiator)
+    }
+
+    pub fn from_stream_with_role(stream: TcpStream, role: Role) -> Self {
+        Self::new(stream, role, None)
+    }
+
+    pub(crate) fn accepted(stream: TcpStream, admission: Admission) -> Self {
+        Self::new(stream, Role::Acceptor, Some(admission))
+    }
+
+    fn new(stream: TcpStream, role: Role, admission: Option<Admission>) -> Self {
         let id = System::generate_app_instance_id();
-        let cancel = CancellationToken::new();
-
-        let address = stream.peer_addr().expect("No stream local addr");
-
-        let (s, r) = channel(1);
-        let (mut read, write) = stream.into_split();
-        let cn = cancel.clone();
-        let idd = id.clone();
-
-        spawn(async move {
-            let mut buf = vec![0u8; BUFFER_SIZE];
-
-            loop {
-                select! {
-                    () = cn.cancelled() => {
-                        debug!("Client dropped. Stop listening: {addr} - {idd}");
-                        break
-                    },
-                    bytes = read.read(&mut buf) => handle_read(bytes, &buf, &s).await,
-                }
-            }
-        });
+        let address = stream.peer_addr().expect("No stream peer addr");
+        let encoding = admission.as_ref().map(Admission::encoding).unwrap_or_default();
+        let link = Link::new(stream, id.clone(), role, admission);
+        link.set_encoding(encoding);
+        let main = Channel::new(link.clone(), "", link.register(0));
 
         debug!("Connection: {id} created");
 
         Self {
-            write: RwLock::new(write),
-            receiver: Mutex::new(r),
-            cancel,
+            main,
+            link,
             address,
             id,
-            _p: PhantomData,
         }
     }
 
     pub async fn send(&self, val: impl Into<Out>) -> Result<()> {
-        let val = val.into();
-        let data = serialize(&val)?;
+        self.main.send(val).await
+    }
 
-        self.write.write().await.write_all(&data).await?;
+    pub async fn send_with_priority(&self, val: impl Into<Out>, priority: Priority) -> Result<()> {
+        self.main.send_with_priority(val, priority).await
+    }
 
-        Ok(())
+    /// Cancel safe, see [`Channel::receive`].
+    pub async fn receive(&self) -> Result<In, ReceiveError> {
+        self.main.receive().await
     }
 
-    pub async fn receive(&self) -> Result<In> {
-        self.receiver
-            .lock()
-            .await
-            .recv()
-            .await
-            .ok_or(anyhow!("Receiving from dropped connection"))?
+    pub async fn receive_with_priority(&self) -> Result<(In, Priority), ReceiveError> {
+        self.main.receive_with_priority().await
     }
 
-    pub async fn local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.write.read().await.local_addr()?)
+    pub async fn receive_timeout(&self, duration: Duration) -> Result<In, ReceiveError> {
+        self.main.receive_timeout(duration).await
     }
 
-    pub async fn peer_addr(&self) -> Result<SocketAddr> {
-        Ok(self.write.read().await.peer_addr()?)
+    pub fn try_receive(&self) -> Result<Option<In>, ReceiveError> {
+        self.main.try_receive()
+    }
+
+    /// Opens a new logical channel over this connection. The peer has to
+    /// accept it with [`Client::accept_channel`] using the same name.
+    pub async fn open_channel<I: Decode, O: Encode>(&self, name: impl ToString) -> Result<Channel<I, O>> {
+        let name = name.to_string();
+        let incoming = self.link.open(&name).await?;
+        Ok(Channel::new(self.link.clone(), name, incoming))
+    }
+
+    /// Waits until the peer opens a channel with this name.
+    pub async fn accept_channel<I: Decode, O: Encode>(&self, name: impl ToString) -> Result<Channel<I, O>> {
+        let name = name.to_string();
+        let incoming = self.link.accept(&name).await?;
+        Ok(Channel::new(self.link.clone(), name, incoming))
+    }
+
+    /// Shapes outgoing messages of all channels to the given bandwidth.
+    /// `None` or 0 removes the limit.
+    pub fn throttle_upload(&self, bytes_per_sec: Option<u64>) {
+        self.link.throttle_upload(bytes_per_sec);
+    }
+
+    /// Codec used for outgoing messages of all channels of this connection.
+    /// Incoming ones are decoded by their [`Header`](crate::serde::Header).
+    pub fn set_encoding(&self, encoding: Encoding) {
+        self.link.set_encoding(encoding);
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.link.encoding()
     }
-}
 
-impl<In, Out> Drop for Client<In, Out> {
-    fn drop(&mut self) {
-        self.cancel.cancel();
+    /// Compression of messages sent by this connection, on all channels.
+    /// [`CompressionStats::get`] sums up all connections.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.link.compression_stats()
+    }
+
+    /// Sends the [`registered_schemas`] to the peer and returns the peer's
+    /// ones, see [`incompatible_schemas`](crate::serde::incompatible_schemas).
+    /// Both peers have to call it.
+    pub async fn exchange_schemas(&self) -> Result<Vec<SchemaInfo>> {
+        let outgoing: Channel<(), Vec<SchemaInfo>> = self.open_channel(SCHEMA_CHANNEL).await?;
+        let incoming: Channel<Vec<SchemaInfo>, ()> = self.accept_channel(SCHEMA_CHANNEL).await?;
+
+        outgoing.send(registered_schemas()).await?;
+
+        Ok(incoming.receive().await?)
+    }
+
+    /// Measures RTT and clock offset to the peer. Pings are answered by the
+    /// peer's connection itself and don't show up in its messages.
+    pub async fn ping(&self) -> Result<PingSample> {
+        self.link.ping().await
+    }
+
+    /// Sends `count` pings one after another with a pause between them.
+    pub async fn measure_latency(&self, count: usize, interval: Duration) -> Result<LatencyStats> {
+        let mut samples = Vec::with_capacity(count);
+
+        for i in 0..count {
+            if i > 0 {
+                sleep(interval).await;
+            }
+            samples.push(self.ping().await?);
+        }
+
+        LatencyStats::from_samples(&samples).ok_or_else(|| anyhow!("Measuring latency with 0 pings"))
+    }
+
+    /// Pings the peer every `interval` until the returned [`Heartbeat`] is
+    /// dropped.
+    pub fn heartbeat(&self, interval: Duration) -> Heartbeat {
+        Heartbeat::start(&self.link, interval)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.link.is_closed()
+    }
+
+    #[allow(clippy::unused_async)]
+    pub async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.link.local_addr())
+    }
+
+    #[allow(clippy::unused_async)]
+    pub async fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.link.peer_addr())
     }
 }
 
@@ -118,40 +218,3 @@ impl<In, Out> std::fmt::Debug for Client<In, Out> {
             .finish()
     }
 }
-
-async fn handle_read<In: DeserializeOwned>(
-    bytes: std::io::Result<usize>,
-    buf: &[u8],
-    sender: &Sender<Result<In>>,
-) {
-    let bytes = match bytes {
-        Ok(b) => b,
-        Err(err) => {
-            error!("Failed to receive from client: {err}");
-            _ = sender
-                .send(Err(anyhow!("Failed to receive from client: {err}")))
-                .await
-                .inspect_err(|e| error!("Failed to send None from client: {e}"));
-            return;
-        }
-    };
-
-    if bytes == 0 {
-        return;
-    }
-
-    match deserialize::<In>(&buf[..bytes]) {
-        Ok(msg) => {
-            _ = sender
-                .send(Ok(msg))
-                .await
-                .inspect_err(|e| error!("Failed to send msg from client: {e}"));
-        }
-        Err(err) => {
-            _ = sender
-                .send(Err(anyhow!("Failed to deserialize from client: {err}")))
-                .await
-                .inspect_err(|e| error!("Failed to send None from client: {e}"));
-        }
-    }
-}
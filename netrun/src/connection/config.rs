@@ -0,0 +1,55 @@
+use crate::{
+    connection::{Cidr, RateLimit},
+    serde::Encoding,
+};
+
+/// Settings applied by [`Server`](crate::Server) to every incoming connection.
+///
+/// Connections rejected by the filters are closed before a
+/// [`Client`](crate::Client) is created for them.
+#[derive(Debug, Default, Clone)]
+pub struct ServerConfig {
+    pub(crate) allow:                  Vec<Cidr>,
+    pub(crate) deny:                   Vec<Cidr>,
+    pub(crate) max_connections:        Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) rate_limit:             Option<RateLimit>,
+    pub(crate) encoding:               Encoding,
+}
+
+impl ServerConfig {
+    /// Once anything is allowed, only matching addresses are accepted.
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    /// Deny list takes priority over the allow list.
+    pub fn deny(mut self, cidr: Cidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Limit applied to incoming messages of each connection separately.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Codec of accepted connections, see
+    /// [`Client::set_encoding`](crate::Client::set_encoding).
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use crate::serde::DecodeError;
+
+/// Why [`crate::Channel::receive`] and the related methods failed.
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// Nothing arrived in time. The channel is still usable.
+    Timeout(Duration),
+    /// The connection or the channel is closed.
+    Closed,
+    /// Reading from the connection failed.
+    Connection(String),
+    /// The peer dropped the message, e.g. because of its rate limit.
+    Rejected(String),
+    /// The message arrived but is not a valid `In`. Usually a
+    /// [`DecodeError`]. The channel is still usable.
+    Decode(anyhow::Error),
+}
+
+impl ReceiveError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Timeout(_))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Self::Closed | Self::Connection(_))
+    }
+
+    pub fn decode_error(&self) -> Option<&DecodeError> {
+        match self {
+            Self::Decode(err) => err.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ReceiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout(duration) => write!(f, "Nothing received in {duration:?}"),
+            Self::Closed => write!(f, "Receiving from dropped connection"),
+            Self::Connection(err) => write!(f, "Failed to receive from client: {err}"),
+            Self::Rejected(reason) => write!(f, "Message rejected by peer: {reason}"),
+            Self::Decode(err) => write!(f, "Failed to deserialize from client: {err}"),
+        }
+    }
+}
+
+impl Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Decode(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
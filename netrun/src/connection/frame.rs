@@ -0,0 +1,233 @@
+use std::io::ErrorKind;
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::connection::{BUFFER_SIZE, Priority};
+
+const HEADER_SIZE: usize = 9;
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+/// Set in the kind byte for frames of the high priority lane.
+const HIGH_PRIORITY: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Data,
+    Open,
+    Close,
+    Credit,
+    Reject,
+    Ping,
+    Pong,
+}
+
+impl FrameKind {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Open => 1,
+            Self::Close => 2,
+            Self::Credit => 3,
+            Self::Reject => 4,
+            Self::Ping => 5,
+            Self::Pong => 6,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => Self::Data,
+            1 => Self::Open,
+            2 => Self::Close,
+            3 => Self::Credit,
+            4 => Self::Reject,
+            5 => Self::Ping,
+            6 => Self::Pong,
+            _ => bail!("Unknown frame kind: {byte}"),
+        })
+    }
+}
+
+/// Single unit on the wire: `[kind: u8][channel: u32][length: u32][payload]`.
+/// The top bit of the kind byte marks the high priority lane.
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub kind:     FrameKind,
+    pub priority: Priority,
+    pub channel:  u32,
+    pub payload:  Vec<u8>,
+}
+
+impl Frame {
+    pub fn data(channel: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind: FrameKind::Data,
+            priority: Priority::Normal,
+            channel,
+            payload,
+        }
+    }
+
+    pub fn open(channel: u32, name: &str) -> Self {
+        Self {
+            kind: FrameKind::Open,
+            priority: Priority::Normal,
+            channel,
+            payload: name.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn close(channel: u32) -> Self {
+        Self {
+            kind: FrameKind::Close,
+            priority: Priority::Normal,
+            channel,
+            payload: vec![],
+        }
+    }
+
+    pub fn credit(channel: u32, amount: u32) -> Self {
+        Self {
+            kind: FrameKind::Credit,
+            priority: Priority::Normal,
+            channel,
+            payload: amount.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Tells the peer one of its messages was dropped. Returns the credit of
+    /// it.
+    pub fn reject(channel: u32, reason: &str) -> Self {
+        Self {
+            kind: FrameKind::Reject,
+            priority: Priority::Normal,
+            channel,
+            payload: reason.as_bytes().to_vec(),
+        }
+    }
+
+    /// Answered by the peer's link with a [`Frame::pong`] carrying the same
+    /// id.
+    pub fn ping(id: u64) -> Self {
+        Self {
+            kind:     FrameKind::Ping,
+            priority: Priority::Normal,
+            channel:  0,
+            payload:  id.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// `received` and `sent` are the peer's wall clock times, see
+    /// [`now_nanos`](crate::connection::ping::now_nanos).
+    pub fn pong(id: u64, received: i64, sent: i64) -> Self {
+        let mut payload = Vec::with_capacity(24);
+        payload.extend_from_slice(&id.to_le_bytes());
+        payload.extend_from_slice(&received.to_le_bytes());
+        payload.extend_from_slice(&sent.to_le_bytes());
+
+        Self {
+            kind: FrameKind::Pong,
+            priority: Priority::Normal,
+            channel: 0,
+            payload,
+        }
+    }
+
+    /// Lane of the data, or the lane credit and rejections are for.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Control frames overtake data so a busy lane can't hold back credit.
+    pub fn write_priority(&self) -> Priority {
+        match self.kind {
+            FrameKind::Data => self.priority,
+            FrameKind::Credit | FrameKind::Reject | FrameKind::Ping | FrameKind::Pong => Priority::High,
+            FrameKind::Open | FrameKind::Close => Priority::Normal,
+        }
+    }
+
+    pub fn credit_amount(&self) -> Result<u32> {
+        let Ok(bytes) = self.payload.as_slice().try_into() else {
+            bail!("Invalid credit frame size: {}", self.payload.len());
+        };
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn ping_id(&self) -> Result<u64> {
+        let Some(bytes) = self.payload.get(..8) else {
+            bail!("Invalid ping frame size: {}", self.payload.len());
+        };
+        Ok(u64::from_le_bytes(bytes.try_into()?))
+    }
+
+    /// Returns `(id, received, sent)`.
+    pub fn pong_times(&self) -> Result<(u64, i64, i64)> {
+        let Ok::<[u8; 24], _>(bytes) = self.payload.as_slice().try_into() else {
+            bail!("Invalid pong frame size: {}", self.payload.len());
+        };
+        Ok((
+            u64::from_le_bytes(bytes[..8].try_into()?),
+            i64::from_le_bytes(bytes[8..16].try_into()?),
+            i64::from_le_bytes(bytes[16..].try_into()?),
+        ))
+    }
+}
+
+pub(crate) async fn write_frame(write: &mut (impl AsyncWrite + Unpin), frame: &Frame) -> Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[0] = match frame.priority {
+        Priority::Normal => frame.kind.to_byte(),
+        Priority::High => frame.kind.to_byte() | HIGH_PRIORITY,
+    };
+    header[1..5].copy_from_slice(&frame.channel.to_le_bytes());
+    header[5..9].copy_from_slice(&u32::try_from(frame.payload.len())?.to_le_bytes());
+
+    // Small frames go out in a single write so Nagle doesn't hold the payload back.
+    if frame.payload.len() <= BUFFER_SIZE {
+        let mut data = Vec::with_capacity(HEADER_SIZE + frame.payload.len());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&frame.payload);
+        write.write_all(&data).await?;
+    } else {
+        write.write_all(&header).await?;
+        write.write_all(&frame.payload).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns `None` when the peer closed the connection.
+pub(crate) async fn read_frame(read: &mut (impl AsyncRead + Unpin)) -> Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    match read.read_exact(&mut header).await {
+        Ok(_) => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let kind = FrameKind::from_byte(header[0] & !HIGH_PRIORITY)?;
+    let priority = if header[0] & HIGH_PRIORITY == 0 {
+        Priority::Normal
+    } else {
+        Priority::High
+    };
+    let channel = u32::from_le_bytes(header[1..5].try_into()?);
+    let len = u32::from_le_bytes(header[5..9].try_into()?) as usize;
+
+    if len > MAX_FRAME_SIZE {
+        bail!("Frame size {len} exceeds limit of {MAX_FRAME_SIZE} bytes");
+    }
+
+    let mut payload = vec![0u8; len];
+    read.read_exact(&mut payload).await?;
+
+    Ok(Some(Frame {
+        kind,
+        priority,
+        channel,
+        payload,
+    }))
+}
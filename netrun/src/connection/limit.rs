@@ -0,0 +1,189 @@
+use std::time::{Duration, Instant};
+
+/// What happens to a message which exceeds the connection rate limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Stop reading from the connection until the budget recovers.
+    #[default]
+    Delay,
+    /// Drop the message and report an error to the sender.
+    Reject,
+    /// Close the connection.
+    Disconnect,
+}
+
+/// Token bucket limits for a single connection. Bursts of up to one second
+/// worth of budget are allowed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    messages: Option<f64>,
+    bytes:    Option<f64>,
+    policy:   LimitPolicy,
+}
+
+impl RateLimit {
+    pub fn new(policy: LimitPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// 0 means no limit.
+    pub fn messages_per_sec(mut self, messages: u32) -> Self {
+        self.messages = Some(f64::from(messages));
+        self
+    }
+
+    /// 0 means no limit.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn bytes_per_sec(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes as f64);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate:    f64,
+    tokens:  f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// `None` for a rate of 0, which means no limit.
+    pub fn new(rate: f64) -> Option<Self> {
+        (rate > 0.0).then(|| Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Amounts bigger than the whole bucket pass once it is full.
+    fn available(&mut self, amount: f64) -> bool {
+        self.refill();
+        self.tokens >= amount.min(self.rate)
+    }
+
+    /// Takes tokens, going into debt if needed. Returns how long to wait
+    /// until the debt is paid.
+    pub fn take(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Pass,
+    Delay(Duration),
+    Reject,
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    messages: Option<TokenBucket>,
+    bytes:    Option<TokenBucket>,
+    policy:   LimitPolicy,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            messages: limit.messages.and_then(TokenBucket::new),
+            bytes:    limit.bytes.and_then(TokenBucket::new),
+            policy:   limit.policy,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn check(&mut self, len: usize) -> Verdict {
+        let len = len as f64;
+
+        let available = self.messages.as_mut().is_none_or(|bucket| bucket.available(1.0))
+            && self.bytes.as_mut().is_none_or(|bucket| bucket.available(len));
+
+        match self.policy {
+            LimitPolicy::Reject if !available => return Verdict::Reject,
+            LimitPolicy::Disconnect if !available => return Verdict::Disconnect,
+            _ => (),
+        }
+
+        let wait = self
+            .messages
+            .as_mut()
+            .map(|bucket| bucket.take(1.0))
+            .max(self.bytes.as_mut().map(|bucket| bucket.take(len)))
+            .unwrap_or_default();
+
+        if wait.is_zero() || self.policy != LimitPolicy::Delay {
+            Verdict::Pass
+        } else {
+            Verdict::Delay(wait)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        LimitPolicy, RateLimit,
+        connection::limit::{Limiter, TokenBucket, Verdict},
+    };
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10.0).unwrap();
+
+        assert_eq!(Duration::ZERO, bucket.take(10.0));
+
+        let wait = bucket.take(5.0);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_limiter_policies() {
+        let mut reject = Limiter::new(RateLimit::new(LimitPolicy::Reject).messages_per_sec(2));
+
+        assert_eq!(Verdict::Pass, reject.check(100));
+        assert_eq!(Verdict::Pass, reject.check(100));
+        assert_eq!(Verdict::Reject, reject.check(100));
+
+        let mut disconnect = Limiter::new(RateLimit::new(LimitPolicy::Disconnect).bytes_per_sec(100));
+
+        assert_eq!(Verdict::Pass, disconnect.check(1000));
+        assert_eq!(Verdict::Disconnect, disconnect.check(1));
+
+        let mut delay = Limiter::new(RateLimit::new(LimitPolicy::Delay).bytes_per_sec(100));
+
+        assert_eq!(Verdict::Pass, delay.check(100));
+        assert!(matches!(delay.check(50), Verdict::Delay(_)));
+
+        assert!(TokenBucket::new(0.0).is_none());
+
+        let mut unlimited =
+            Limiter::new(RateLimit::new(LimitPolicy::Reject).messages_per_sec(0).bytes_per_sec(0));
+        for _ in 0..100 {
+            assert_eq!(Verdict::Pass, unlimited.check(1000));
+        }
+    }
+}
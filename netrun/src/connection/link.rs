@@ -0,0 +1,679 @@
+use core::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, error, trace, warn};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    select, spawn,
+    sync::{
+        Notify, Semaphore,
+        mpsc::{
+            Receiver, Sender, UnboundedReceiver, UnboundedSender, channel,
+            error::{TryRecvError, TrySendError},
+            unbounded_channel,
+        },
+        oneshot,
+    },
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::{
+        BUFFER_SIZE, Encode, Priority, ReceiveError, Role,
+        accept::Admission,
+        frame::{Frame, FrameKind, read_frame, write_frame},
+        limit::{Limiter, TokenBucket, Verdict},
+        ping::{PingSample, Pong, now_nanos},
+        priority::Fairness,
+    },
+    serde::{CompressionCounters, CompressionStats, Encoding},
+};
+
+/// How many messages a peer may send on a channel before the receiver grants
+/// more credit.
+pub(crate) const WINDOW: u32 = 32;
+
+/// Channels the peer opened which weren't accepted yet. Further ones are
+/// closed right away.
+pub(crate) const MAX_PENDING: usize = 64;
+
+/// Answers to the peer waiting for the writer. Further ones are dropped.
+const MAX_REPLIES: usize = 64;
+
+pub(crate) type Item = Result<Vec<u8>, ReceiveError>;
+
+/// Incoming messages of a channel, one queue per [`Priority`] lane.
+pub(crate) struct Inbox {
+    lanes:    [Receiver<Item>; 2],
+    fairness: Fairness,
+}
+
+impl Inbox {
+    pub fn try_recv(&mut self) -> Result<(Priority, Item), TryRecvError> {
+        let mut result = Err(TryRecvError::Disconnected);
+
+        for priority in self.fairness.order() {
+            match self.lanes[priority.index()].try_recv() {
+                Ok(item) => {
+                    self.fairness.served(priority);
+                    return Ok((priority, item));
+                }
+                Err(TryRecvError::Empty) => result = Err(TryRecvError::Empty),
+                Err(TryRecvError::Disconnected) => (),
+            }
+        }
+
+        result
+    }
+}
+
+/// Receiving side of a logical channel which was registered on the link.
+pub(crate) struct Incoming {
+    pub id:      u32,
+    pub inbox:   Inbox,
+    /// Notified when something is put into the inbox or it is closed.
+    pub arrived: Arc<Notify>,
+    pub credits: [Arc<Semaphore>; 2],
+}
+
+struct Lane {
+    sender:  Sender<Item>,
+    credits: Arc<Semaphore>,
+}
+
+struct Slot {
+    lanes:   [Lane; 2],
+    arrived: Arc<Notify>,
+}
+
+impl Slot {
+    fn lane(&self, priority: Priority) -> &Lane {
+        &self.lanes[priority.index()]
+    }
+
+    fn deliver(&self, priority: Priority, item: Item) -> Result<(), TrySendError<Item>> {
+        self.lane(priority).sender.try_send(item)?;
+        self.arrived.notify_waiters();
+        Ok(())
+    }
+
+    fn close(&self) {
+        for lane in &self.lanes {
+            lane.credits.close();
+        }
+    }
+}
+
+/// Dropping the senders closes the inbox, receivers waiting on it have to
+/// find out.
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.arrived.notify_waiters();
+    }
+}
+
+struct Registry {
+    /// Parity of the channel ids this side opens.
+    parity:  u32,
+    slots:   Mutex<BTreeMap<u32, Slot>>,
+    pending: Mutex<BTreeMap<String, VecDeque<Incoming>>>,
+    opened:  Notify,
+    pings:   Mutex<BTreeMap<u64, oneshot::Sender<Pong>>>,
+}
+
+impl Registry {
+    fn new(role: Role) -> Self {
+        Self {
+            parity:  role.first_channel() % 2,
+            slots:   Mutex::default(),
+            pending: Mutex::default(),
+            opened:  Notify::new(),
+            pings:   Mutex::default(),
+        }
+    }
+
+    fn register(&self, id: u32) -> Incoming {
+        let lane = || {
+            // One extra slot so a read error can always be delivered.
+            let (sender, receiver) = channel(WINDOW as usize + 1);
+            let credits = Arc::new(Semaphore::new(WINDOW as usize));
+            (Lane { sender, credits }, receiver)
+        };
+
+        let (normal, normal_inbox) = lane();
+        let (high, high_inbox) = lane();
+
+        let credits = [normal.credits.clone(), high.credits.clone()];
+        let arrived = Arc::new(Notify::new());
+
+        self.slots.lock().insert(
+            id,
+            Slot {
+                lanes:   [normal, high],
+                arrived: arrived.clone(),
+            },
+        );
+
+        Incoming {
+            id,
+            inbox: Inbox {
+                lanes:    [normal_inbox, high_inbox],
+                fairness: Fairness::default(),
+            },
+            arrived,
+            credits,
+        }
+    }
+
+    /// Returns a frame to answer the peer with.
+    fn dispatch(&self, frame: Frame) -> Option<Frame> {
+        match frame.kind {
+            FrameKind::Data => {
+                let slots = self.slots.lock();
+                let Some(slot) = slots.get(&frame.channel) else {
+                    warn!("Data for unknown channel: {}", frame.channel);
+                    return None;
+                };
+                _ = slot
+                    .deliver(frame.priority, Ok(frame.payload))
+                    .inspect_err(|e| error!("Peer exceeded credit on channel {}: {e}", frame.channel));
+            }
+            FrameKind::Open => {
+                let name = String::from_utf8_lossy(&frame.payload).to_string();
+                trace!("Peer opened channel {name}: {}", frame.channel);
+
+                // Taking over the main channel or one of ours would drop it.
+                let id = frame.channel;
+                if id == 0 || id % 2 == self.parity || self.slots.lock().contains_key(&id) {
+                    warn!("Peer opened channel {name} with invalid id: {id}");
+                    return Some(Frame::close(id));
+                }
+
+                let mut pending = self.pending.lock();
+                if pending.values().map(VecDeque::len).sum::<usize>() >= MAX_PENDING {
+                    warn!(
+                        "Too many channels waiting to be accepted. Closing {name}: {}",
+                        frame.channel
+                    );
+                    return Some(Frame::close(frame.channel));
+                }
+
+                let incoming = self.register(frame.channel);
+                pending.entry(name).or_default().push_back(incoming);
+                self.opened.notify_waiters();
+            }
+            FrameKind::Close => {
+                trace!("Peer closed channel: {}", frame.channel);
+                if let Some(slot) = self.slots.lock().remove(&frame.channel) {
+                    slot.close();
+                }
+            }
+            FrameKind::Reject => {
+                let reason = String::from_utf8_lossy(&frame.payload);
+                if let Some(slot) = self.slots.lock().get(&frame.channel) {
+                    slot.lane(frame.priority).credits.add_permits(1);
+                    _ = slot
+                        .deliver(frame.priority, Err(ReceiveError::Rejected(reason.to_string())))
+                        .inspect_err(|e| error!("Failed to send rejection from client: {e}"));
+                }
+            }
+            FrameKind::Credit => match frame.credit_amount() {
+                Ok(amount) => {
+                    if let Some(slot) = self.slots.lock().get(&frame.channel) {
+                        slot.lane(frame.priority).credits.add_permits(amount as usize);
+                    }
+                }
+                Err(err) => error!("{err}"),
+            },
+            FrameKind::Pong => match frame.pong_times() {
+                Ok((id, received, sent)) => {
+                    let pong = Pong {
+                        received,
+                        sent,
+                        arrived: now_nanos(),
+                        arrived_at: Instant::now(),
+                    };
+                    if let Some(sender) = self.pings.lock().remove(&id) {
+                        _ = sender.send(pong);
+                    }
+                }
+                Err(err) => error!("{err}"),
+            },
+            FrameKind::Ping => error!("Ping must be answered by the link"),
+        }
+
+        None
+    }
+
+    fn take_pending(&self, name: &str) -> Option<Incoming> {
+        let mut pending = self.pending.lock();
+        let queue = pending.get_mut(name)?;
+        let incoming = queue.pop_front();
+        if queue.is_empty() {
+            pending.remove(name);
+        }
+        incoming
+    }
+
+    /// Both lanes get the error, so it arrives even when one of them is
+    /// full.
+    fn fail(&self, err: &anyhow::Error) {
+        for slot in self.slots.lock().values() {
+            for priority in [Priority::Normal, Priority::High] {
+                _ = slot
+                    .deliver(priority, Err(ReceiveError::Connection(err.to_string())))
+                    .inspect_err(|e| error!("Failed to send error from client: {e}"));
+            }
+        }
+    }
+
+    fn close_all(&self) {
+        for (_, slot) in std::mem::take(&mut *self.slots.lock()) {
+            slot.close();
+        }
+        self.pending.lock().clear();
+        self.pings.lock().clear();
+        self.opened.notify_waiters();
+    }
+}
+
+/// Frame waiting for the writer task. `written` gets the result, replies to
+/// the peer have nobody waiting for them.
+struct Queued {
+    frame:   Frame,
+    written: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// Physical connection shared by all logical channels multiplexed over it.
+///
+/// Frames are written by a single task, so a cancelled send never leaves
+/// half a frame on the stream.
+pub(crate) struct Link {
+    lanes:       [UnboundedSender<Queued>; 2],
+    local_addr:  SocketAddr,
+    peer_addr:   SocketAddr,
+    registry:    Arc<Registry>,
+    next_id:     AtomicU32,
+    pings:       AtomicU64,
+    upload:      Mutex<Option<TokenBucket>>,
+    encoding:    Mutex<Encoding>,
+    compression: Arc<CompressionCounters>,
+    cancel:      CancellationToken,
+    closed:      CancellationToken,
+}
+
+impl Link {
+    /// Channel ids are split by parity so both peers can open channels without
+    /// coordination. Id 0 is the default channel of every connection.
+    /// Accepted connections carry their server admission until they close.
+    pub fn new(stream: TcpStream, id: String, role: Role, admission: Option<Admission>) -> Arc<Self> {
+        let local_addr = stream.local_addr().expect("Failed to get stream local_addr");
+        let peer_addr = stream.peer_addr().expect("No stream peer addr");
+
+        let cancel = CancellationToken::new();
+        let closed = CancellationToken::new();
+        let registry = Arc::new(Registry::new(role));
+
+        let (read, write) = stream.into_split();
+
+        // The read loop never writes itself, so a peer which doesn't read
+        // can't stop it from reading.
+        let (queue, replies) = channel(MAX_REPLIES);
+        let (normal, normal_lane) = unbounded_channel();
+        let (high, high_lane) = unbounded_channel();
+
+        let outgoing = Outgoing {
+            lanes: [normal_lane, high_lane],
+            replies,
+            fairness: Fairness::default(),
+        };
+        let replies = Replies {
+            queue,
+            disconnect: CancellationToken::new(),
+        };
+
+        let cn = cancel.clone();
+        let dc = replies.disconnect.clone();
+
+        spawn(async move {
+            select! {
+                () = cn.cancelled() => (),
+                () = write_queued(outgoing, &dc, write) => (),
+            }
+        });
+
+        let cn = cancel.clone();
+        let cl = closed.clone();
+        let reg = registry.clone();
+
+        spawn(async move {
+            select! {
+                () = cn.cancelled() => debug!("Client dropped. Stop listening: {local_addr} - {id}"),
+                () = receive(read, &reg, &replies, admission.as_ref()) => debug!("Connection closed: {peer_addr} - {id}"),
+            }
+
+            reg.close_all();
+            cl.cancel();
+        });
+
+        Arc::new(Self {
+            lanes: [normal, high],
+            local_addr,
+            peer_addr,
+            registry,
+            next_id: AtomicU32::new(role.first_channel()),
+            pings: AtomicU64::default(),
+            upload: Mutex::new(None),
+            encoding: Mutex::default(),
+            compression: Arc::default(),
+            cancel,
+            closed,
+        })
+    }
+
+    pub fn register(&self, id: u32) -> Incoming {
+        self.registry.register(id)
+    }
+
+    pub fn unregister(&self, id: u32) {
+        self.registry.slots.lock().remove(&id);
+    }
+
+    pub async fn open(&self, name: &str) -> Result<Incoming> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let incoming = self.register(id);
+        self.send(Frame::open(id, name)).await?;
+        Ok(incoming)
+    }
+
+    pub async fn accept(&self, name: &str) -> Result<Incoming> {
+        loop {
+            let opened = self.registry.opened.notified();
+
+            if let Some(incoming) = self.registry.take_pending(name) {
+                return Ok(incoming);
+            }
+
+            if self.is_closed() {
+                return Err(anyhow!("Accepting channel {name} on closed connection"));
+            }
+
+            opened.await;
+        }
+    }
+
+    /// Measures a round trip to the peer's link. Pings are answered by the
+    /// peer automatically, it doesn't have to receive anything.
+    ///
+    /// The ping is queued for the writer right away, only waiting for the
+    /// pong can be cancelled.
+    pub async fn ping(&self) -> Result<PingSample> {
+        let id = self.pings.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+
+        self.registry.pings.lock().insert(id, sender);
+
+        let _pending = PendingPing {
+            registry: &self.registry,
+            id,
+        };
+
+        if self.is_closed() {
+            bail!("Ping on closed connection");
+        }
+
+        let sent = now_nanos();
+        let sent_at = Instant::now();
+
+        drop(self.queue(Frame::ping(id))?);
+
+        let pong = receiver.await.map_err(|_| anyhow!("Connection closed before pong"))?;
+
+        Ok(PingSample::new(sent, sent_at, &pong))
+    }
+
+    pub async fn send(&self, frame: Frame) -> Result<()> {
+        self.send_then(frame, || ()).await
+    }
+
+    /// Waits until the frame is written. `queued` runs once it is handed to
+    /// the writer, from then on it is written whole even if this future is
+    /// dropped.
+    #[allow(clippy::cast_precision_loss)]
+    pub async fn send_then(&self, frame: Frame, queued: impl FnOnce()) -> Result<()> {
+        if frame.kind == FrameKind::Data {
+            let wait = self
+                .upload
+                .lock()
+                .as_mut()
+                .map(|bucket| bucket.take(frame.payload.len() as f64));
+
+            if let Some(wait) = wait
+                && !wait.is_zero()
+            {
+                sleep(wait).await;
+            }
+        }
+
+        let written = self.queue(frame)?;
+        queued();
+
+        written.await.map_err(|_| anyhow!("Connection closed before sending"))?
+    }
+
+    fn queue(&self, frame: Frame) -> Result<oneshot::Receiver<Result<()>>> {
+        let (sender, written) = oneshot::channel();
+
+        self.lanes[frame.write_priority().index()]
+            .send(Queued {
+                frame,
+                written: Some(sender),
+            })
+            .map_err(|_| anyhow!("Sending on closed connection"))?;
+
+        Ok(written)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throttle_upload(&self, bytes_per_sec: Option<u64>) {
+        *self.upload.lock() = bytes_per_sec.and_then(|rate| TokenBucket::new(rate as f64));
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        *self.encoding.lock()
+    }
+
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.lock() = encoding;
+    }
+
+    /// Encodes with the current encoding, counting it in
+    /// [`Link::compression_stats`].
+    pub fn encode(&self, val: impl Encode) -> Result<Vec<u8>> {
+        let encoding = self.encoding();
+        self.compression.count(|| val.encode_with(encoding))
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression.get()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+/// What the read loop wants written to the peer.
+struct Replies {
+    queue:      Sender<Frame>,
+    disconnect: CancellationToken,
+}
+
+impl Replies {
+    fn send(&self, frame: Frame) {
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(frame) {
+            warn!("Peer doesn't read replies. Dropping one");
+        }
+    }
+}
+
+/// Frames waiting for [`write_queued`]. High priority ones overtake queued
+/// normal ones.
+struct Outgoing {
+    lanes:    [UnboundedReceiver<Queued>; 2],
+    replies:  Receiver<Frame>,
+    fairness: Fairness,
+}
+
+impl Outgoing {
+    /// Replies go first, they are small and the peer may be waiting for
+    /// them.
+    fn try_next(&mut self) -> Option<Queued> {
+        if let Ok(frame) = self.replies.try_recv() {
+            return Some(Queued { frame, written: None });
+        }
+
+        for priority in self.fairness.order() {
+            if let Ok(queued) = self.lanes[priority.index()].try_recv() {
+                self.fairness.served(priority);
+                return Some(queued);
+            }
+        }
+
+        None
+    }
+
+    async fn next(&mut self) -> Option<Queued> {
+        if let Some(queued) = self.try_next() {
+            return Some(queued);
+        }
+
+        let [normal, high] = &mut self.lanes;
+
+        let (priority, queued) = select! {
+            Some(frame) = self.replies.recv() => return Some(Queued { frame, written: None }),
+            Some(queued) = high.recv() => (Priority::High, queued),
+            Some(queued) = normal.recv() => (Priority::Normal, queued),
+            else => return None,
+        };
+
+        self.fairness.served(priority);
+        Some(queued)
+    }
+}
+
+/// Writes queued frames one by one until the link is dropped, shutting the
+/// stream down when asked to.
+async fn write_queued(mut outgoing: Outgoing, disconnect: &CancellationToken, mut write: OwnedWriteHalf) {
+    let forward = async {
+        while let Some(Queued { frame, written }) = outgoing.next().await {
+            let result = write_frame(&mut write, &frame).await;
+
+            match written {
+                Some(written) => _ = written.send(result),
+                None => _ = result.inspect_err(|e| error!("Failed to answer peer: {e}")),
+            }
+        }
+    };
+
+    // Replies are closed right after a disconnect request, which has to win.
+    select! {
+        biased;
+        () = disconnect.cancelled() => (),
+        () = forward => return,
+    }
+
+    _ = write.shutdown().await;
+}
+
+async fn receive(read: OwnedReadHalf, registry: &Registry, replies: &Replies, admission: Option<&Admission>) {
+    let mut read = BufReader::with_capacity(BUFFER_SIZE, read);
+    let mut limiter = admission.and_then(Admission::rate_limit).map(Limiter::new);
+
+    loop {
+        let frame = match read_frame(&mut read).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to receive from client: {err}");
+                registry.fail(&err);
+                return;
+            }
+        };
+
+        if frame.kind == FrameKind::Ping {
+            let received = now_nanos();
+            match frame.ping_id() {
+                Ok(id) => replies.send(Frame::pong(id, received, now_nanos())),
+                Err(err) => error!("{err}"),
+            }
+            continue;
+        }
+
+        if frame.kind == FrameKind::Data
+            && let Some(limiter) = &mut limiter
+        {
+            match limiter.check(frame.payload.len()) {
+                Verdict::Pass => (),
+                Verdict::Delay(wait) => sleep(wait).await,
+                Verdict::Reject => {
+                    admission.inspect(|a| a.limited());
+                    let reject =
+                        Frame::reject(frame.channel, "rate limit exceeded").with_priority(frame.priority);
+                    replies.send(reject);
+                    continue;
+                }
+                Verdict::Disconnect => {
+                    admission.inspect(|a| a.limited());
+                    warn!("Rate limit exceeded. Disconnecting");
+                    replies.disconnect.cancel();
+                    return;
+                }
+            }
+        }
+
+        if let Some(frame) = registry.dispatch(frame) {
+            replies.send(frame);
+        }
+    }
+}
+
+/// Forgets a ping which was cancelled before its pong arrived.
+struct PendingPing<'a> {
+    registry: &'a Registry,
+    id:       u64,
+}
+
+impl Drop for PendingPing<'_> {
+    fn drop(&mut self) {
+        self.registry.pings.lock().remove(&self.id);
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
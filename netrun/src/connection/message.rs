@@ -0,0 +1,168 @@
+use std::ops::Deref;
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::serde::{Codec, Encoding};
+
+/// Anything that can be sent over a [`Client`](crate::Client) connection.
+///
+/// Every serializable type goes through the [`Encoding`] of the connection.
+/// [`Bytes`] skips it and is sent as is.
+pub trait Encode: Send + 'static {
+    fn encode_with(self, encoding: Encoding) -> Result<Vec<u8>>;
+
+    /// Uses the default [`Encoding`].
+    fn encode(self) -> Result<Vec<u8>>
+    where Self: Sized {
+        self.encode_with(Encoding::default())
+    }
+}
+
+/// Anything that can be received over a [`Client`](crate::Client)
+/// connection, see [`Encode`].
+pub trait Decode: Sized + Send + 'static {
+    fn decode_with(data: Vec<u8>, encoding: Encoding) -> Result<Self>;
+
+    /// Uses the default [`Encoding`].
+    fn decode(data: Vec<u8>) -> Result<Self> {
+        Self::decode_with(data, Encoding::default())
+    }
+}
+
+/// Travels both ways.
+pub trait Message: Encode + Decode {}
+
+impl<T: Encode + Decode> Message for T {}
+
+impl<T: Serialize + Send + 'static> Encode for T {
+    fn encode_with(self, encoding: Encoding) -> Result<Vec<u8>> {
+        Codec::encode(&encoding, &self)
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> Decode for T {
+    fn decode_with(data: Vec<u8>, encoding: Encoding) -> Result<Self> {
+        Codec::decode(&encoding, &data)
+    }
+}
+
+/// Raw binary payload which bypasses JSON and compression.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Bytes {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Encode for Bytes {
+    fn encode_with(self, _: Encoding) -> Result<Vec<u8>> {
+        Ok(self.0)
+    }
+}
+
+impl Decode for Bytes {
+    fn decode_with(data: Vec<u8>, _: Encoding) -> Result<Self> {
+        Ok(Self(data))
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(data: &[u8]) -> Self {
+        Self(data.to_vec())
+    }
+}
+
+impl From<Bytes> for Vec<u8> {
+    fn from(bytes: Bytes) -> Self {
+        bytes.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+    use test_log::test;
+
+    use crate::{Bytes, Client, Encode, Server};
+
+    #[derive(Serialize)]
+    struct Request(i32);
+
+    #[derive(Deserialize)]
+    struct IncomingRequest(i32);
+
+    #[derive(Serialize)]
+    struct Reply(String);
+
+    #[derive(Deserialize)]
+    struct IncomingReply(String);
+
+    #[test]
+    fn test_bytes_are_not_encoded() -> Result<()> {
+        let data = vec![1, 2, 3, 4, 5];
+
+        assert_eq!(data, Bytes(data.clone()).encode()?);
+        assert_ne!(data, data.clone().encode()?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_raw_connection() -> Result<()> {
+        let server = Server::<Bytes, Bytes>::start(57782).await?;
+        let client = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57782)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let blob: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        client.send(blob.clone()).await?;
+        assert_eq!(blob, connection.receive().await?.into_inner());
+
+        connection.send(&b"pong"[..]).await?;
+        assert_eq!(b"pong", &*client.receive().await?);
+
+        let json = client.open_channel::<String, String>("json").await?;
+        let server_json = connection.accept_channel::<String, String>("json").await?;
+
+        json.send("hello").await?;
+        assert_eq!("hello", server_json.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_one_way_types() -> Result<()> {
+        let server = Server::<IncomingRequest, Reply>::start(57833).await?;
+        let client = Client::<IncomingReply, Request>::connect((Ipv4Addr::LOCALHOST, 57833)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        client.send(Request(5)).await?;
+        let request = connection.receive().await?;
+        connection.send(Reply(request.0.to_string())).await?;
+
+        assert_eq!("5", client.receive().await?.0);
+
+        Ok(())
+    }
+}
@@ -1,24 +1,78 @@
 const BUFFER_SIZE: usize = 1024 * 16;
 
+mod accept;
+mod balance;
+mod bench;
+mod channel;
 mod client;
+mod config;
+mod error;
+mod frame;
+mod limit;
+mod link;
+mod message;
+mod outbox;
+mod ping;
+mod pool;
+mod priority;
+mod proxy;
+mod pubsub;
+mod remote_log;
 mod server;
 mod service;
+mod session;
+mod transfer;
 
+pub use accept::{Cidr, Rejection, ServerMetrics};
+pub use balance::*;
+pub use bench::*;
+pub use channel::*;
 pub use client::*;
+pub use config::*;
+pub use error::ReceiveError;
+pub use limit::{LimitPolicy, RateLimit};
+pub use message::*;
+pub use outbox::*;
+pub use ping::{Heartbeat, LatencyStats, PingSample};
+pub use pool::*;
+pub use priority::Priority;
+pub use proxy::*;
+pub use pubsub::*;
+pub use remote_log::*;
 pub use server::*;
 pub use service::*;
+pub use session::*;
+pub use transfer::*;
 
 #[cfg(test)]
 mod test {
-    use std::net::Ipv4Addr;
+    use std::{
+        net::Ipv4Addr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
 
-    use anyhow::Result;
+    use anyhow::{Result, bail};
     use pretty_assertions::assert_eq;
     use test_log::test;
-    use tokio::{sync::OnceCell, task::JoinSet};
+    use tokio::{
+        io::{AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::OnceCell,
+        task::JoinSet,
+        time::{sleep, timeout},
+    };
 
     use super::*;
-    use crate::Retry;
+    use crate::{
+        Retry,
+        connection::{
+            frame::{Frame, FrameKind, read_frame, write_frame},
+            link::{MAX_PENDING, WINDOW},
+            priority::MAX_STREAK,
+        },
+        serde::{CompressionStats, DecodeStage, DecompressError, Encoding, Format, Key, serialize},
+    };
 
     async fn server() -> Result<&'static Server<i32, bool>> {
         static SERVER: OnceCell<Server<i32, bool>> = OnceCell::const_new();
@@ -62,7 +116,8 @@ mod test {
         let err = client.receive().await.err().unwrap();
 
         assert_eq!(
-            r"Failed to deserialize from client: invalid type: integer `1`, expected a boolean at line 1 column 1",
+            "Failed to deserialize from client: Failed to decode bool from 6 bytes at parse stage: invalid \
+             type: integer `1`, expected a boolean at line 1 column 1. Payload: \"1\"",
             err.to_string()
         );
 
@@ -119,6 +174,537 @@ mod test {
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn test_channels() -> Result<()> {
+        let server = Server::<i32, bool>::start(57780).await?;
+        let client = Client::<bool, i32>::connect((Ipv4Addr::LOCALHOST, 57780)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let names = client.open_channel::<usize, String>("names").await?;
+        let flags = client.open_channel::<String, bool>("flags").await?;
+
+        let server_flags = connection.accept_channel::<bool, String>("flags").await?;
+        let server_names = connection.accept_channel::<String, usize>("names").await?;
+
+        names.send("Roma").await?;
+        flags.send(true).await?;
+        client.send(5).await?;
+
+        assert_eq!(true, server_flags.receive().await?);
+        assert_eq!("Roma", server_names.receive().await?);
+        assert_eq!(5, connection.receive().await?);
+
+        server_names.send(4usize).await?;
+        server_flags.send("ok").await?;
+        connection.send(false).await?;
+
+        assert_eq!(false, client.receive().await?);
+        assert_eq!("ok", flags.receive().await?);
+        assert_eq!(4, names.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_channel_flow_control() -> Result<()> {
+        let server = Server::<(), ()>::start(57781).await?;
+        let client = Client::<(), ()>::connect((Ipv4Addr::LOCALHOST, 57781)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let bulk = client.open_channel::<(), i32>("bulk").await?;
+        let control = client.open_channel::<(), i32>("control").await?;
+
+        let server_bulk = connection.accept_channel::<i32, ()>("bulk").await?;
+        let server_control = connection.accept_channel::<i32, ()>("control").await?;
+
+        for i in 0..i32::try_from(WINDOW)? {
+            bulk.send(i).await?;
+        }
+
+        let blocked = timeout(Duration::from_millis(200), bulk.send(-1)).await;
+        assert!(blocked.is_err(), "Send must wait for credit when window is full");
+
+        control.send(777).await?;
+        assert_eq!(777, server_control.receive().await?);
+
+        for i in 0..i32::try_from(WINDOW)? {
+            assert_eq!(i, server_bulk.receive().await?);
+        }
+
+        bulk.send(100).await?;
+        assert_eq!(100, server_bulk.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_pending_channels_limit() -> Result<()> {
+        let server = Server::<(), ()>::start(57831).await?;
+        let client = Client::<(), ()>::connect((Ipv4Addr::LOCALHOST, 57831)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let mut channels = vec![];
+        for _ in 0..MAX_PENDING {
+            channels.push(client.open_channel::<(), i32>("ignored").await?);
+        }
+
+        let extra = client.open_channel::<(), i32>("extra").await?;
+        assert!(matches!(
+            timeout(Duration::from_secs(5), extra.receive()).await?,
+            Err(ReceiveError::Closed)
+        ));
+        assert!(extra.send(1).await.is_err());
+
+        // Accepting makes room again.
+        connection.accept_channel::<i32, ()>("ignored").await?;
+        let next = client.open_channel::<(), i32>("next").await?;
+        let server_next = connection.accept_channel::<i32, ()>("next").await?;
+        next.send(5).await?;
+        assert_eq!(5, server_next.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_stream_roles() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 57832)).await?;
+        let (stream, accepted) = tokio::try_join!(
+            tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, 57832)),
+            listener.accept()
+        )?;
+
+        let first = Client::<i32, i32>::from_stream(stream);
+        let second = Client::<i32, i32>::from_stream_with_role(accepted.0, Role::Acceptor);
+
+        let a = first.open_channel::<i32, i32>("a").await?;
+        let b = second.open_channel::<i32, i32>("b").await?;
+        let remote_a = second.accept_channel::<i32, i32>("a").await?;
+        let remote_b = first.accept_channel::<i32, i32>("b").await?;
+
+        a.send(1).await?;
+        b.send(2).await?;
+        assert_eq!(1, remote_a.receive().await?);
+        assert_eq!(2, remote_b.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_accept_filter() -> Result<()> {
+        let server = Server::<i32, i32>::start_with_config(
+            57785,
+            ServerConfig::default().deny("127.0.0.0/8".parse()?),
+        )
+        .await?;
+
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57785)).await?;
+
+        assert!(client.receive().await.is_err());
+        assert!(client.is_closed());
+
+        assert_eq!(
+            ServerMetrics {
+                accepted: 0,
+                rejected: 1,
+                active:   0,
+                limited:  0,
+            },
+            server.metrics()
+        );
+
+        let server = Server::<i32, i32>::start_with_config(
+            57786,
+            ServerConfig::default().allow("10.0.0.0/8".parse()?).allow("127.0.0.1".parse()?),
+        )
+        .await?;
+
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57786)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        client.send(5).await?;
+        assert_eq!(5, connection.receive().await?);
+        assert_eq!(1, server.metrics().accepted);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_connection_limits() -> Result<()> {
+        let server =
+            Server::<i32, i32>::start_with_config(57787, ServerConfig::default().max_connections_per_ip(1))
+                .await?;
+
+        let first = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57787)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let second = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57787)).await?;
+        assert!(second.receive().await.is_err());
+
+        assert_eq!(1, server.metrics().active);
+        assert_eq!(1, server.metrics().rejected);
+
+        drop(first);
+        drop(connection);
+
+        Retry::times(10)
+            .run(|| async {
+                sleep(Duration::from_millis(50)).await;
+                if server.metrics().active == 0 {
+                    Ok(())
+                } else {
+                    bail!("Connection is still active")
+                }
+            })
+            .await?;
+
+        let third = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57787)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        third.send(3).await?;
+        assert_eq!(3, connection.receive().await?);
+
+        let server =
+            Server::<i32, i32>::start_with_config(57788, ServerConfig::default().max_connections(0)).await?;
+
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57788)).await?;
+        assert!(client.receive().await.is_err());
+        assert_eq!(1, server.metrics().rejected);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_rate_limit_policies() -> Result<()> {
+        let limit = |policy| ServerConfig::default().rate_limit(RateLimit::new(policy).messages_per_sec(5));
+
+        let server = Server::<i32, i32>::start_with_config(57789, limit(LimitPolicy::Reject)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57789)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        for i in 0..10 {
+            client.send(i).await?;
+        }
+
+        for i in 0..5 {
+            assert_eq!(i, connection.receive().await?);
+        }
+
+        for _ in 0..5 {
+            assert_eq!(
+                "Message rejected by peer: rate limit exceeded",
+                client.receive().await.err().unwrap().to_string()
+            );
+        }
+
+        assert_eq!(5, server.metrics().limited);
+
+        let server = Server::<i32, i32>::start_with_config(57790, limit(LimitPolicy::Disconnect)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57790)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        for i in 0..6 {
+            client.send(i).await?;
+        }
+
+        for i in 0..5 {
+            assert_eq!(i, connection.receive().await?);
+        }
+
+        assert!(connection.receive().await.is_err());
+        assert!(client.receive().await.is_err());
+
+        let server = Server::<i32, i32>::start_with_config(57791, limit(LimitPolicy::Delay)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57791)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let start = Instant::now();
+
+        for i in 0..7 {
+            client.send(i).await?;
+        }
+
+        for i in 0..7 {
+            assert_eq!(i, connection.receive().await?);
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_upload_throttle() -> Result<()> {
+        let server = Server::<Bytes, Bytes>::start(57792).await?;
+        let client = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57792)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        client.throttle_upload(Some(100_000));
+
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            client.send(vec![0u8; 26_000]).await?;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        for _ in 0..5 {
+            assert_eq!(26_000, connection.receive().await?.len());
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_receive_timeout_and_try_receive() -> Result<()> {
+        let server = Server::<i32, i32>::start(57813).await?;
+        let client = Client::<bool, i32>::connect((Ipv4Addr::LOCALHOST, 57813)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        assert!(matches!(client.try_receive(), Ok(None)));
+
+        let err = client.receive_timeout(Duration::from_millis(50)).await.err().unwrap();
+        assert!(err.is_timeout());
+
+        connection.send(1).await?;
+        assert!(matches!(
+            client.receive_timeout(Duration::from_secs(1)).await,
+            Err(ReceiveError::Decode(_))
+        ));
+
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57813)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        connection.send(2).await?;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(Some(2), client.try_receive()?);
+
+        drop(connection);
+        sleep(Duration::from_millis(50)).await;
+
+        assert!(matches!(client.try_receive(), Err(ReceiveError::Closed)));
+        assert!(client.receive().await.err().unwrap().is_closed());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_concurrent_receivers() -> Result<()> {
+        let server = Server::<i32, i32>::start(57839).await?;
+        let client = Arc::new(Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57839)).await?);
+        let connection = server.wait_for_new_connection().await;
+
+        let receivers = [client.clone(), client.clone()]
+            .map(|client| tokio::spawn(async move { client.receive().await }));
+        sleep(Duration::from_millis(50)).await;
+
+        assert!(matches!(client.try_receive(), Ok(None)));
+
+        for i in 0..3 {
+            connection.send(i).await?;
+        }
+        sleep(Duration::from_millis(50)).await;
+
+        let mut received = vec![client.try_receive()?.expect("Third message is waiting")];
+        for receiver in receivers {
+            received.push(receiver.await??);
+        }
+        received.sort_unstable();
+
+        assert_eq!(vec![0, 1, 2], received);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_read_error_on_full_lane() -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 57841)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57841)).await?;
+        let (mut peer, _) = listener.accept().await?;
+
+        // Ignores credit and fills the normal lane up to its reserved slot.
+        for i in 0..=WINDOW {
+            write_frame(&mut peer, &Frame::data(0, i.encode()?)).await?;
+        }
+        peer.write_all(&[0xFF; 9]).await?;
+        sleep(Duration::from_millis(50)).await;
+
+        let mut received = 0;
+        let mut failed = false;
+
+        loop {
+            match client.receive().await {
+                Ok(_) => received += 1,
+                Err(ReceiveError::Connection(_)) => failed = true,
+                Err(err) if err.is_closed() => break,
+                Err(err) => bail!("Unexpected error: {err}"),
+            }
+        }
+
+        assert_eq!(WINDOW + 1, received);
+        assert!(failed);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_invalid_channel_open() -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 57847)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57847)).await?;
+        let (peer, _) = listener.accept().await?;
+        let (read, mut write) = peer.into_split();
+        let mut read = BufReader::new(read);
+
+        let own = client.open_channel::<i32, i32>("own").await?;
+        let Some(open) = read_frame(&mut read).await? else {
+            bail!("Expected open frame");
+        };
+
+        // The main channel, the channel we opened and our parity.
+        for id in [0, open.channel, open.channel + 2] {
+            write_frame(&mut write, &Frame::open(id, "taken")).await?;
+            let Some(close) = read_frame(&mut read).await? else {
+                bail!("Expected close frame");
+            };
+            assert_eq!((FrameKind::Close, id), (close.kind, close.channel));
+        }
+
+        write_frame(&mut write, &Frame::data(0, 5.encode()?)).await?;
+        write_frame(&mut write, &Frame::data(open.channel, 6.encode()?)).await?;
+
+        assert_eq!(5, client.receive().await?);
+        assert_eq!(6, own.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_cancelled_send_keeps_credit() -> Result<()> {
+        let server = Server::<i32, i32>::start(57848).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57848)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        client.throttle_upload(Some(1));
+
+        for _ in 0..=WINDOW {
+            assert!(timeout(Duration::from_millis(10), client.send(-1)).await.is_err());
+        }
+
+        client.throttle_upload(None);
+
+        for i in 0..i32::try_from(WINDOW)? {
+            timeout(Duration::from_secs(1), client.send(i)).await??;
+        }
+
+        for i in 0..i32::try_from(WINDOW)? {
+            assert_eq!(i, connection.receive().await?);
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_peer_not_reading_replies() -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 57843)).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57843)).await?;
+        let (mut peer, _) = listener.accept().await?;
+
+        // Pongs pile up on a peer which never reads them.
+        tokio::spawn(async move {
+            let mut pings = vec![];
+            for id in 0..200_000 {
+                write_frame(&mut pings, &Frame::ping(id)).await?;
+            }
+            peer.write_all(&pings).await?;
+            write_frame(&mut peer, &Frame::data(0, 5.encode()?)).await?;
+            sleep(Duration::from_secs(5)).await;
+            anyhow::Ok(())
+        });
+
+        assert_eq!(5, client.receive_timeout(Duration::from_secs(3)).await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_receive_cancel_safety() -> Result<()> {
+        let server = Server::<i32, i32>::start(57814).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57814)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let count = WINDOW as i32 * 4;
+
+        tokio::spawn(async move {
+            for i in 0..count {
+                connection.send(i).await?;
+                if i % 3 == 0 {
+                    sleep(Duration::from_millis(1)).await;
+                }
+            }
+            sleep(Duration::from_secs(1)).await;
+            anyhow::Ok(())
+        });
+
+        let mut received = vec![];
+
+        while received.len() < count as usize {
+            tokio::select! {
+                val = client.receive() => received.push(val?),
+                () = sleep(Duration::from_micros(200)) => (),
+            }
+        }
+
+        assert_eq!((0..count).collect::<Vec<_>>(), received);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_priority_lanes() -> Result<()> {
+        let server = Server::<i32, i32>::start(57820).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57820)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let window = i32::try_from(WINDOW)?;
+
+        for i in 0..window {
+            client.send(i).await?;
+        }
+
+        assert!(timeout(Duration::from_millis(100), client.send(-1)).await.is_err());
+
+        for i in 0..window {
+            client.send_with_priority(100 + i, Priority::High).await?;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut received = vec![];
+        for _ in 0..window * 2 {
+            received.push(connection.receive_with_priority().await?);
+        }
+
+        let streak = MAX_STREAK as usize;
+
+        assert_eq!((100, Priority::High), received[0]);
+        assert!(received[..streak].iter().all(|(_, priority)| *priority == Priority::High));
+        assert_eq!((0, Priority::Normal), received[streak]);
+
+        let (high, normal): (Vec<_>, Vec<_>) =
+            received.into_iter().partition(|(_, priority)| *priority == Priority::High);
+
+        assert_eq!(
+            (100..100 + window).collect::<Vec<_>>(),
+            high.into_iter().map(|(i, _)| i).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            (0..window).collect::<Vec<_>>(),
+            normal.into_iter().map(|(i, _)| i).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn connection_debug_impl() -> Result<()> {
         let server = Server::<i32, bool>::start(55550).await?;
@@ -134,4 +720,107 @@ mod test {
 
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn test_encoding() -> Result<()> {
+        let encoding = Encoding::raw(Format::Postcard);
+
+        let server = Server::<Vec<String>, Vec<String>>::start_with_config(
+            57828,
+            ServerConfig::default().encoding(encoding),
+        )
+        .await?;
+
+        let client = Client::<Vec<String>, Vec<String>>::connect((Ipv4Addr::LOCALHOST, 57828)).await?;
+        client.set_encoding(encoding);
+        let connection = server.wait_for_new_connection().await;
+
+        assert_eq!(encoding, connection.encoding());
+
+        let words = vec!["hello".to_string(), "postcard".to_string()];
+
+        client.send(words.clone()).await?;
+        assert_eq!(words, connection.receive().await?);
+
+        connection.send(words.clone()).await?;
+        assert_eq!(words, client.receive().await?);
+
+        // Each message names its own encoding.
+        client.set_encoding(Encoding::compressed(Format::Json));
+        client.send(words.clone()).await?;
+        assert_eq!(words, connection.receive().await?);
+
+        client.set_encoding(Encoding::legacy());
+        client.send(words).await?;
+        assert!(matches!(connection.receive().await, Err(ReceiveError::Decode(_))));
+
+        // The small enveloped message was skipped, legacy ones are always
+        // compressed.
+        let stats = client.compression_stats();
+        assert_eq!((1, 1), (stats.compressed, stats.skipped));
+        assert_eq!(CompressionStats::default(), connection.compression_stats());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_encrypted_channels() -> Result<()> {
+        let encoding = Encoding::compressed(Format::Json).encrypt(&Key::generate(70_010));
+
+        let server = Server::<i32, i32>::start(57845).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57845)).await?;
+        client.set_encoding(encoding);
+        let connection = server.wait_for_new_connection().await;
+
+        let early = client.open_channel::<(), i32>("early").await?;
+        let busy = client.open_channel::<(), i32>("busy").await?;
+
+        let server_early = connection.accept_channel::<i32, ()>("early").await?;
+        let server_busy = connection.accept_channel::<i32, ()>("busy").await?;
+
+        early.send(-1).await?;
+
+        // Far more messages than a replay window holds are decoded before
+        // the early one.
+        for i in 0..100 {
+            busy.send(i).await?;
+            assert_eq!(i, server_busy.receive().await?);
+        }
+
+        assert_eq!(-1, server_early.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_hostile_compressed_data() -> Result<()> {
+        let server = Server::<i32, i32>::start(57829).await?;
+        let client = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57829)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        // Claims 4 GB.
+        client.send(Bytes(vec![0xFF, 0xFF, 0xFF, 0xFF, 0])).await?;
+        let err = connection.receive().await.unwrap_err();
+        let Some(err) = err.decode_error() else {
+            bail!("Expected decode error");
+        };
+        assert_eq!(DecodeStage::Decompress, err.stage);
+        assert!(matches!(
+            err.cause::<DecompressError>(),
+            Some(DecompressError::TooLarge { .. })
+        ));
+
+        client.send(Bytes(vec![1, 2])).await?;
+        assert!(matches!(connection.receive().await, Err(ReceiveError::Decode(_))));
+
+        // The connection survives.
+        client.send(Bytes(serialize(5)?)).await?;
+        assert_eq!(5, connection.receive().await?);
+
+        connection.set_encoding(Encoding::default().max_size(4));
+        client.send(Bytes(serialize(vec![0; 1000])?)).await?;
+        assert!(matches!(connection.receive().await, Err(ReceiveError::Decode(_))));
+
+        Ok(())
+    }
 }
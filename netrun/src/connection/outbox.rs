@@ -0,0 +1,614 @@
+use std::{
+    any::type_name,
+    collections::BTreeMap,
+    io::ErrorKind,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use log::{debug, error, warn};
+use parking_lot::Mutex as SyncMutex;
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    select, spawn,
+    sync::{
+        Mutex, Notify,
+        mpsc::{Receiver, Sender, channel},
+    },
+    time::sleep,
+    try_join,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::{Bytes, Client, Decode, Encode, Server},
+    serde::{MAX_STREAM_SENDERS, ReplayWindows},
+};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const ID_FILE: &str = "id";
+const SEQ_FILE: &str = "seq";
+/// Sequence numbers reserved on disk at once.
+const SEQ_BLOCK: u64 = 1024;
+const EXTENSION: &str = "msg";
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| u64::try_from(since.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
+
+/// Written aside and renamed, so a crash never leaves half a file. Leftovers
+/// are removed by [`Store::open`].
+async fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Random 128 bit id, so outboxes of different hosts never share the
+/// server's duplicate tracking.
+fn new_id() -> String {
+    let mut id = [0; 16];
+    OsRng.fill_bytes(&mut id);
+    BASE64_STANDARD.encode(id)
+}
+
+/// `[id length: u8][id][seq: u64][payload]`
+fn encode_packet(id: &str, seq: u64, payload: &[u8]) -> Result<Bytes> {
+    let id_len = u8::try_from(id.len()).map_err(|_| anyhow!("Outbox id is too long"))?;
+
+    let mut data = Vec::with_capacity(1 + id.len() + 8 + payload.len());
+    data.push(id_len);
+    data.extend_from_slice(id.as_bytes());
+    data.extend_from_slice(&seq.to_le_bytes());
+    data.extend_from_slice(payload);
+    Ok(Bytes(data))
+}
+
+fn decode_packet(data: &[u8]) -> Result<(String, u64, &[u8])> {
+    let Some((&id_len, rest)) = data.split_first() else {
+        bail!("Empty outbox packet");
+    };
+
+    let id_len = usize::from(id_len);
+
+    let (Some(id), Some(seq)) = (rest.get(..id_len), rest.get(id_len..id_len + 8)) else {
+        bail!("Outbox packet is too short: {}", data.len());
+    };
+
+    Ok((
+        String::from_utf8(id.to_vec())?,
+        u64::from_le_bytes(seq.try_into()?),
+        &rest[id_len + 8..],
+    ))
+}
+
+/// Next sequence number and the first one not reserved on disk yet.
+///
+/// The reservation survives restarts even when no messages are left, so
+/// numbers are never reused and the server doesn't take new messages for
+/// duplicates.
+struct Sequence {
+    next:     u64,
+    reserved: u64,
+}
+
+/// Messages on disk, one file per message: `[created: u64][payload]`.
+struct Store {
+    dir:      PathBuf,
+    /// Sequence number to creation time in milliseconds.
+    pending:  SyncMutex<BTreeMap<u64, u64>>,
+    /// Held while a message is written, so messages become pending in
+    /// sequence order.
+    next_seq: Mutex<Sequence>,
+}
+
+impl Store {
+    async fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut pending = BTreeMap::new();
+        let mut entries = fs::read_dir(&dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            match path.extension().and_then(|ext| ext.to_str()) {
+                // Interrupted before it was stored. Its push never returned.
+                Some("tmp") => fs::remove_file(&path).await?,
+                Some(EXTENSION) => {
+                    let Some(seq) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) else {
+                        warn!("Unexpected file in outbox: {}", path.display());
+                        continue;
+                    };
+
+                    let data = fs::read(&path).await?;
+                    let Some(created) = data.get(..8) else {
+                        warn!("Removing corrupted outbox message: {}", path.display());
+                        fs::remove_file(&path).await?;
+                        continue;
+                    };
+
+                    pending.insert(seq, u64::from_le_bytes(created.try_into()?));
+                }
+                _ => (),
+            }
+        }
+
+        let reserved = match fs::read_to_string(dir.join(SEQ_FILE)).await {
+            Ok(seq) => seq
+                .trim()
+                .parse()
+                .map_err(|err| anyhow!("Corrupted outbox {SEQ_FILE} file: {err}"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => 1,
+            Err(err) => return Err(err.into()),
+        };
+
+        let next = pending.keys().next_back().map_or(1, |last| last + 1).max(reserved);
+
+        let store = Self {
+            dir,
+            pending: SyncMutex::new(pending),
+            next_seq: Mutex::new(Sequence { next, reserved: next }),
+        };
+
+        store.reserve(&mut *store.next_seq.lock().await).await?;
+
+        Ok(store)
+    }
+
+    async fn reserve(&self, sequence: &mut Sequence) -> Result<()> {
+        let reserved = sequence.reserved + SEQ_BLOCK;
+
+        write_file(&self.dir.join(SEQ_FILE), reserved.to_string().as_bytes()).await?;
+
+        sequence.reserved = reserved;
+
+        Ok(())
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:020}.{EXTENSION}"))
+    }
+
+    async fn push(&self, payload: Vec<u8>) -> Result<u64> {
+        let mut next_seq = self.next_seq.lock().await;
+
+        if next_seq.next >= next_seq.reserved {
+            self.reserve(&mut next_seq).await?;
+        }
+
+        let seq = next_seq.next;
+
+        let created = now_millis();
+
+        let mut data = Vec::with_capacity(8 + payload.len());
+        data.extend_from_slice(&created.to_le_bytes());
+        data.extend_from_slice(&payload);
+
+        write_file(&self.path(seq), &data).await?;
+
+        self.pending.lock().insert(seq, created);
+        next_seq.next += 1;
+
+        Ok(seq)
+    }
+
+    fn next_after(&self, seq: u64) -> Option<u64> {
+        self.pending.lock().range(seq + 1..).next().map(|(seq, _)| *seq)
+    }
+
+    /// Returns `None` if the message was acknowledged meanwhile.
+    async fn read(&self, seq: u64) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(seq)).await {
+            Ok(data) if data.len() >= 8 => Ok(Some(data[8..].to_vec())),
+            Ok(_) => bail!("Corrupted outbox message: {seq}"),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The file goes first, so a drained [`Outbox::depth`] means nothing is
+    /// left on disk.
+    async fn ack(&self, seq: u64) -> Result<()> {
+        if !self.pending.lock().contains_key(&seq) {
+            return Ok(());
+        }
+
+        match fs::remove_file(self.path(seq)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
+
+        self.pending.lock().remove(&seq);
+
+        Ok(())
+    }
+}
+
+struct Shared {
+    id:     String,
+    store:  Store,
+    pushed: Notify,
+}
+
+impl Shared {
+    async fn deliver(&self, address: &str) {
+        loop {
+            match Client::<Bytes, Bytes>::connect(address).await {
+                Ok(client) => {
+                    debug!("Outbox {} connected to {address}", self.id);
+                    _ = self
+                        .resend(&client)
+                        .await
+                        .inspect_err(|err| debug!("Outbox {} disconnected: {err}", self.id));
+                }
+                Err(err) => debug!("Outbox {} failed to connect to {address}: {err}", self.id),
+            }
+
+            sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    /// Sends everything that wasn't acknowledged, starting from the oldest,
+    /// and then new messages as they are pushed.
+    async fn resend(&self, client: &Client<Bytes, Bytes>) -> Result<()> {
+        try_join!(self.send_pending(client), self.receive_acks(client)).map(|_| ())
+    }
+
+    async fn send_pending(&self, client: &Client<Bytes, Bytes>) -> Result<()> {
+        let mut cursor = 0;
+
+        loop {
+            let Some(seq) = self.store.next_after(cursor) else {
+                self.pushed.notified().await;
+                continue;
+            };
+
+            cursor = seq;
+
+            if let Some(payload) = self.store.read(seq).await? {
+                client.send(encode_packet(&self.id, seq, &payload)?).await?;
+            }
+        }
+    }
+
+    async fn receive_acks(&self, client: &Client<Bytes, Bytes>) -> Result<()> {
+        loop {
+            let ack = client.receive().await?;
+            let seq = u64::from_le_bytes(ack.as_ref().try_into()?);
+            self.store.ack(seq).await?;
+        }
+    }
+}
+
+/// Durable queue of outgoing messages for an [`OutboxServer`].
+///
+/// Every message is written to disk before [`Outbox::push`] returns and is
+/// removed only after the server acknowledged it. Unacknowledged messages are
+/// resent after reconnecting, also after the process restarts with the same
+/// directory.
+pub struct Outbox<T> {
+    shared: Arc<Shared>,
+    cancel: CancellationToken,
+    _p:     PhantomData<Mutex<T>>,
+}
+
+impl<T: Encode> Outbox<T> {
+    pub async fn open(dir: impl AsRef<Path>, address: impl ToString) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let store = Store::open(dir.clone()).await?;
+
+        let id_path = dir.join(ID_FILE);
+        let id = match fs::read_to_string(&id_path).await {
+            Ok(id) => id,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let id = new_id();
+                write_file(&id_path, id.as_bytes()).await?;
+                id
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let shared = Arc::new(Shared {
+            id,
+            store,
+            pushed: Notify::new(),
+        });
+
+        let cancel = CancellationToken::new();
+
+        let sh = shared.clone();
+        let cn = cancel.clone();
+        let address = address.to_string();
+
+        spawn(async move {
+            select! {
+                () = cn.cancelled() => (),
+                () = sh.deliver(&address) => (),
+            }
+        });
+
+        Ok(Self {
+            shared,
+            cancel,
+            _p: PhantomData,
+        })
+    }
+
+    /// Returns once the message is stored on disk.
+    pub async fn push(&self, val: impl Into<T>) -> Result<()> {
+        self.shared.store.push(val.into().encode()?).await?;
+        self.shared.pushed.notify_one();
+        Ok(())
+    }
+
+    /// Messages not yet acknowledged by the server.
+    pub fn depth(&self) -> usize {
+        self.shared.store.pending.lock().len()
+    }
+
+    pub fn oldest_age(&self) -> Option<Duration> {
+        let created = *self.shared.store.pending.lock().values().next()?;
+        Some(Duration::from_millis(now_millis().saturating_sub(created)))
+    }
+}
+
+impl<T> Drop for Outbox<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> std::fmt::Debug for Outbox<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct(&format!("Outbox<{}>", type_name::<T>()))
+            .field("id", &self.shared.id)
+            .field("dir", &self.shared.store.dir)
+            .finish()
+    }
+}
+
+/// Receives messages from [`Outbox`]es and acknowledges them.
+///
+/// A message is acknowledged once it is queued for [`OutboxServer::receive`].
+/// Messages resent after a lost acknowledgement are recognized and skipped
+/// while the server is running.
+pub struct OutboxServer<T> {
+    received: Mutex<Receiver<T>>,
+    cancel:   CancellationToken,
+    port:     u16,
+}
+
+impl<T: Decode> OutboxServer<T> {
+    pub async fn start(port: u16) -> Result<Self> {
+        let server = Server::<Bytes, Bytes>::start(port).await?;
+        let (sender, received) = channel(1);
+        let cancel = CancellationToken::new();
+
+        let delivered = Arc::new(SyncMutex::new(BTreeMap::<String, u64>::new()));
+        let cn = cancel.clone();
+
+        spawn(async move {
+            loop {
+                let connection = select! {
+                    () = cn.cancelled() => break,
+                    connection = server.wait_for_new_connection() => connection,
+                };
+
+                let sender = sender.clone();
+                let delivered = delivered.clone();
+                let cn = cn.clone();
+
+                spawn(async move {
+                    select! {
+                        () = cn.cancelled() => (),
+                        result = Self::serve(&connection, &sender, &delivered) => {
+                            _ = result.inspect_err(|err| debug!("Outbox disconnected: {err}"));
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            received: Mutex::new(received),
+            cancel,
+            port,
+        })
+    }
+
+    pub async fn receive(&self) -> Result<T> {
+        self.received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Outbox server stopped"))
+    }
+
+    async fn serve(
+        connection: &Client<Bytes, Bytes>,
+        sender: &Sender<T>,
+        delivered: &SyncMutex<BTreeMap<String, u64>>,
+    ) -> Result<()> {
+        let replays = Arc::new(ReplayWindows::new(MAX_STREAM_SENDERS));
+
+        loop {
+            let packet = connection.receive().await?;
+            let (id, seq, payload) = decode_packet(&packet)?;
+
+            // The slot is reserved first, so claiming the message and queueing
+            // it happen without an await in between. A concurrent connection
+            // of the same outbox sees it as a duplicate.
+            let slot = sender.reserve().await.map_err(|_| anyhow!("Outbox server stopped"))?;
+
+            let claimed = {
+                let mut delivered = delivered.lock();
+                if delivered.get(&id).is_some_and(|last| *last >= seq) {
+                    false
+                } else {
+                    delivered.insert(id.clone(), seq);
+                    true
+                }
+            };
+
+            if claimed {
+                match replays.check(|| T::decode(payload.to_vec())) {
+                    Ok(val) => slot.send(val),
+                    // Resending won't help, so it is acknowledged anyway.
+                    Err(err) => error!("Failed to decode message {seq} from outbox {id}: {err}"),
+                }
+            }
+
+            connection.send(Bytes(seq.to_le_bytes().to_vec())).await?;
+        }
+    }
+}
+
+impl<T> Drop for OutboxServer<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> std::fmt::Debug for OutboxServer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct(&format!("OutboxServer<{}>", type_name::<T>()))
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::{
+        time::{sleep, timeout},
+        try_join,
+    };
+
+    use super::encode_packet;
+    use crate::{Bytes, Client, Encode, Outbox, OutboxServer, System};
+
+    #[test(tokio::test)]
+    async fn test_outbox() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("netrun-outbox-{}", System::generate_app_instance_id()));
+
+        let outbox = Outbox::<String>::open(&dir, "127.0.0.1:57827").await?;
+        let id = std::fs::read_to_string(dir.join("id"))?;
+
+        assert_eq!(24, id.len());
+        assert_eq!(None, outbox.oldest_age());
+
+        for i in 0..3 {
+            outbox.push(format!("offline {i}")).await?;
+        }
+
+        sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(3, outbox.depth());
+        assert!(outbox.oldest_age().unwrap() >= Duration::from_millis(20));
+
+        // Restart.
+        drop(outbox);
+        let outbox = Outbox::<String>::open(&dir, "127.0.0.1:57827").await?;
+
+        assert_eq!(3, outbox.depth());
+        assert_eq!(id, std::fs::read_to_string(dir.join("id"))?);
+        outbox.push("after restart").await?;
+
+        let server = OutboxServer::<String>::start(57827).await?;
+
+        for expected in ["offline 0", "offline 1", "offline 2", "after restart"] {
+            assert_eq!(expected, server.receive().await?);
+        }
+
+        outbox.push("online").await?;
+        assert_eq!("online", server.receive().await?);
+
+        for _ in 0..50 {
+            if outbox.depth() == 0 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(0, outbox.depth());
+        assert_eq!(None, outbox.oldest_age());
+
+        // Only the id and seq files are left.
+        assert_eq!(2, std::fs::read_dir(&dir)?.count());
+
+        _ = std::fs::remove_dir_all(dir);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_outbox_restart_after_drain() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("netrun-outbox-{}", System::generate_app_instance_id()));
+
+        let server = OutboxServer::<String>::start(57844).await?;
+
+        for message in ["before restart", "after restart", "after second restart"] {
+            let outbox = Outbox::<String>::open(&dir, "127.0.0.1:57844").await?;
+            outbox.push(message).await?;
+
+            assert_eq!(message, timeout(Duration::from_secs(5), server.receive()).await??);
+
+            for _ in 0..50 {
+                if outbox.depth() == 0 {
+                    break;
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+
+            assert_eq!(0, outbox.depth());
+        }
+
+        _ = std::fs::remove_dir_all(dir);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_outbox_concurrent_duplicates() -> Result<()> {
+        let server = OutboxServer::<String>::start(57851).await?;
+
+        let first = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57851)).await?;
+        let second = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57851)).await?;
+
+        // The same message resent over a new connection while the old one
+        // is still delivering it.
+        let packet = encode_packet("outbox", 1, &"resent".to_string().encode()?)?;
+        try_join!(first.send(packet.clone()), second.send(packet))?;
+
+        assert_eq!("resent", server.receive().await?);
+        try_join!(first.receive(), second.receive())?;
+
+        first.send(encode_packet("outbox", 2, &"next".to_string().encode()?)?).await?;
+        assert_eq!("next", server.receive().await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::{
+    select, spawn,
+    time::{interval, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::connection::link::Link;
+
+/// Samples kept by [`Heartbeat`] for its statistics.
+const HEARTBEAT_WINDOW: usize = 100;
+/// Missed pings in a row after which [`Heartbeat`] reports the peer as dead.
+const MAX_MISSED: u32 = 3;
+
+/// Wall clock time in nanoseconds since the Unix epoch.
+pub(crate) fn now_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| i64::try_from(since.as_nanos()).unwrap_or(i64::MAX))
+        .unwrap_or_default()
+}
+
+/// Peer timestamps of a ping and when its answer arrived.
+#[derive(Debug)]
+pub(crate) struct Pong {
+    pub received:   i64,
+    pub sent:       i64,
+    pub arrived:    i64,
+    pub arrived_at: Instant,
+}
+
+/// Result of a single ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingSample {
+    /// Round trip time without the time the peer spent answering.
+    pub rtt:       Duration,
+    /// Estimated difference of the peer's clock from the local one in
+    /// nanoseconds. Positive when the peer's clock is ahead.
+    pub offset_ns: i64,
+}
+
+impl PingSample {
+    /// NTP style estimation from the four timestamps of a ping.
+    pub(crate) fn new(sent: i64, sent_at: Instant, pong: &Pong) -> Self {
+        let elapsed = pong.arrived_at.duration_since(sent_at);
+        let answering = Duration::from_nanos(u64::try_from(pong.sent - pong.received).unwrap_or_default());
+
+        Self {
+            rtt:       elapsed.saturating_sub(answering),
+            offset_ns: i64::midpoint(pong.received - sent, pong.sent - pong.arrived),
+        }
+    }
+}
+
+/// RTT statistics over a set of pings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub samples:   usize,
+    pub min:       Duration,
+    pub avg:       Duration,
+    pub p99:       Duration,
+    /// Mean difference between RTTs of consecutive pings.
+    pub jitter:    Duration,
+    /// Clock offset from the ping with the lowest RTT, which is the least
+    /// affected by asymmetric delays.
+    pub offset_ns: i64,
+}
+
+impl LatencyStats {
+    /// Returns `None` for no samples.
+    pub fn from_samples(samples: &[PingSample]) -> Option<Self> {
+        let fastest = samples.iter().min_by_key(|sample| sample.rtt)?;
+        let count = u32::try_from(samples.len()).unwrap_or(u32::MAX);
+
+        let mut sorted: Vec<_> = samples.iter().map(|sample| sample.rtt).collect();
+        sorted.sort_unstable();
+
+        let jitter = if samples.len() < 2 {
+            Duration::ZERO
+        } else {
+            samples
+                .windows(2)
+                .map(|pair| pair[0].rtt.abs_diff(pair[1].rtt))
+                .sum::<Duration>()
+                / (count - 1)
+        };
+
+        Some(Self {
+            samples: samples.len(),
+            min: fastest.rtt,
+            avg: sorted.iter().sum::<Duration>() / count,
+            p99: sorted[(samples.len() * 99).div_ceil(100) - 1],
+            jitter,
+            offset_ns: fastest.offset_ns,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Beats {
+    samples: Mutex<VecDeque<PingSample>>,
+    missed:  AtomicU32,
+}
+
+/// Pings the peer periodically in the background and keeps RTT statistics
+/// of the latest pings.
+///
+/// Stops when dropped or when the connection is closed.
+pub struct Heartbeat {
+    beats:  Arc<Beats>,
+    link:   Weak<Link>,
+    cancel: CancellationToken,
+}
+
+impl Heartbeat {
+    /// A ping not answered within the interval counts as missed.
+    pub(crate) fn start(link: &Arc<Link>, period: Duration) -> Self {
+        let beats = Arc::new(Beats::default());
+        let cancel = CancellationToken::new();
+
+        let bt = beats.clone();
+        let cn = cancel.clone();
+        let lk = Arc::downgrade(link);
+
+        spawn(async move {
+            let mut ticks = interval(period);
+
+            loop {
+                select! {
+                    () = cn.cancelled() => return,
+                    _ = ticks.tick() => (),
+                }
+
+                let Some(link) = lk.upgrade().filter(|link| !link.is_closed()) else {
+                    return;
+                };
+
+                // Only waiting for the pong times out, the ping itself is
+                // always written whole, even to a stalled peer.
+                match timeout(period, link.ping()).await {
+                    Ok(Ok(sample)) => {
+                        bt.missed.store(0, Ordering::Relaxed);
+                        let mut samples = bt.samples.lock();
+                        if samples.len() == HEARTBEAT_WINDOW {
+                            samples.pop_front();
+                        }
+                        samples.push_back(sample);
+                    }
+                    Ok(Err(err)) => {
+                        debug!("Heartbeat ping failed: {err}");
+                        bt.missed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        debug!("Heartbeat ping timed out");
+                        bt.missed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+
+        Self {
+            beats,
+            link: Arc::downgrade(link),
+            cancel,
+        }
+    }
+
+    /// `false` once the connection is closed or the peer missed several pings
+    /// in a row.
+    pub fn is_alive(&self) -> bool {
+        self.link.upgrade().is_some_and(|link| !link.is_closed())
+            && self.beats.missed.load(Ordering::Relaxed) < MAX_MISSED
+    }
+
+    pub fn last(&self) -> Option<PingSample> {
+        self.beats.samples.lock().back().copied()
+    }
+
+    pub fn stats(&self) -> Option<LatencyStats> {
+        LatencyStats::from_samples(self.beats.samples.lock().make_contiguous())
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl std::fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("alive", &self.is_alive())
+            .field("last", &self.last())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use anyhow::{Result, bail};
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::{
+        io::BufReader,
+        net::TcpListener,
+        time::{sleep, timeout},
+    };
+
+    use crate::{
+        Bytes, Client, LatencyStats, PingSample, Server,
+        connection::frame::{FrameKind, read_frame},
+    };
+
+    fn sample(rtt_ms: u64, offset_ns: i64) -> PingSample {
+        PingSample {
+            rtt: Duration::from_millis(rtt_ms),
+            offset_ns,
+        }
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        assert_eq!(None, LatencyStats::from_samples(&[]));
+
+        let mut samples = vec![sample(10, 5), sample(20, 7), sample(4, -3), sample(10, 1)];
+        samples.extend((0..96).map(|_| sample(10, 0)));
+
+        let stats = LatencyStats::from_samples(&samples).unwrap();
+
+        assert_eq!(100, stats.samples);
+        assert_eq!(Duration::from_millis(4), stats.min);
+        assert_eq!(Duration::from_millis(1004) / 100, stats.avg);
+        assert_eq!(Duration::from_millis(10), stats.p99);
+        assert_eq!(Duration::from_millis(10 + 16 + 6) / 99, stats.jitter);
+        assert_eq!(-3, stats.offset_ns);
+
+        let stats = LatencyStats::from_samples(&[sample(20, 0), sample(30, 0)]).unwrap();
+        assert_eq!(Duration::from_millis(30), stats.p99);
+        assert_eq!(Duration::from_millis(10), stats.jitter);
+    }
+
+    #[test(tokio::test)]
+    async fn test_ping_and_heartbeat() -> Result<()> {
+        let server = Server::<i32, i32>::start(57825).await?;
+        let client = Client::<i32, i32>::connect((Ipv4Addr::LOCALHOST, 57825)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let sample = client.ping().await?;
+        assert!(sample.rtt < Duration::from_secs(1));
+        // Same machine, same clock.
+        assert!(sample.offset_ns.abs() < 50_000_000, "{sample:?}");
+
+        let stats = client.measure_latency(20, Duration::from_millis(1)).await?;
+        assert_eq!(20, stats.samples);
+        assert!(stats.min <= stats.avg && stats.avg <= stats.p99);
+
+        // Pings don't interfere with messages.
+        client.send(5).await?;
+        assert_eq!(5, connection.receive().await?);
+
+        let heartbeat = connection.heartbeat(Duration::from_millis(20));
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(heartbeat.is_alive());
+        assert!(heartbeat.last().is_some());
+        assert!(heartbeat.stats().unwrap().samples >= 3);
+
+        drop(client);
+        sleep(Duration::from_millis(100)).await;
+
+        assert!(!heartbeat.is_alive());
+        assert!(connection.ping().await.is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_heartbeat_stalled_peer() -> Result<()> {
+        const SIZE: usize = 1024 * 1024;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 57849)).await?;
+        let client = Client::<Bytes, Bytes>::connect((Ipv4Addr::LOCALHOST, 57849)).await?;
+        let (peer, _) = listener.accept().await?;
+
+        let heartbeat = client.heartbeat(Duration::from_millis(20));
+
+        // Far more than the socket buffers hold, the peer doesn't read yet.
+        let send = tokio::spawn(async move {
+            for i in 0..8 {
+                client.send(vec![i; SIZE]).await?;
+            }
+            anyhow::Ok(client)
+        });
+
+        sleep(Duration::from_millis(300)).await;
+        assert!(!heartbeat.is_alive());
+
+        // Timed out pings didn't break the framing.
+        let mut read = BufReader::new(peer);
+        let mut received = 0;
+
+        while received < 8 {
+            let Some(frame) = timeout(Duration::from_secs(5), read_frame(&mut read)).await?? else {
+                bail!("Stream ended");
+            };
+            if frame.kind == FrameKind::Data {
+                assert_eq!(vec![received; SIZE], frame.payload);
+                received += 1;
+            }
+        }
+
+        send.await??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,520 @@
+use std::{
+    any::type_name,
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use log::{debug, trace};
+use parking_lot::Mutex;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::connection::{Client, Decode, Encode};
+
+const DEFAULT_MAX_SIZE: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_mins(1);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Limits of a [`Pool`], checked by [`Pool::with_config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    min_size:     usize,
+    max_size:     usize,
+    idle_timeout: Duration,
+    ping_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_size:     0,
+            max_size:     DEFAULT_MAX_SIZE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            ping_timeout: Some(DEFAULT_PING_TIMEOUT),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Amount of idle connections per address which are never evicted.
+    /// Filled with [`Pool::warm_up`].
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// 8 by default.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Idle connections are pinged on checkout and dropped if the pong
+    /// doesn't arrive in time. 500 ms by default, `None` skips the ping.
+    pub fn ping_timeout(mut self, ping_timeout: Option<Duration>) -> Self {
+        self.ping_timeout = ping_timeout;
+        self
+    }
+}
+
+struct Idle<In, Out> {
+    client: Client<In, Out>,
+    since:  Instant,
+}
+
+struct Slot<In, Out> {
+    idle:    VecDeque<Idle<In, Out>>,
+    permits: Arc<Semaphore>,
+}
+
+struct Shared<In, Out> {
+    slots:        Mutex<HashMap<String, Slot<In, Out>>>,
+    min_size:     usize,
+    max_size:     usize,
+    idle_timeout: Duration,
+    ping_timeout: Option<Duration>,
+}
+
+/// Neither closed nor holding a message nobody asked for. A stray response
+/// would be taken as the reply to the next request.
+fn reusable<In: Decode, Out: Encode>(client: &Client<In, Out>) -> bool {
+    !client.is_closed() && matches!(client.try_receive(), Ok(None))
+}
+
+impl<In, Out> Shared<In, Out> {
+    /// Drops connections idle for longer than the timeout, keeping at least
+    /// `min_size` per address.
+    fn evict(&self, slots: &mut HashMap<String, Slot<In, Out>>) {
+        for (address, slot) in slots.iter_mut() {
+            while slot.idle.len() > self.min_size
+                && slot.idle.front().is_some_and(|idle| idle.since.elapsed() >= self.idle_timeout)
+            {
+                trace!("Evicting idle connection to {address}");
+                slot.idle.pop_front();
+            }
+        }
+    }
+}
+
+/// Reusable [`Client`] connections keyed by address.
+///
+/// At most `max_size` connections per address exist at once, checkouts wait
+/// for a free one. Closed connections, ones with unread messages and ones
+/// whose peer doesn't answer a ping on checkout are dropped instead of being
+/// reused, and idle ones are evicted whenever the pool is used.
+pub struct Pool<In, Out> {
+    shared: Arc<Shared<In, Out>>,
+}
+
+impl<In: Decode, Out: Encode> Pool<In, Out> {
+    pub fn new() -> Self {
+        Self::from_config(PoolConfig::default())
+    }
+
+    pub fn with_config(config: PoolConfig) -> Result<Self> {
+        if config.max_size == 0 {
+            bail!("Pool max size can't be 0");
+        }
+        if config.min_size > config.max_size {
+            bail!(
+                "Pool min size {} is bigger than max size {}",
+                config.min_size,
+                config.max_size
+            );
+        }
+        Ok(Self::from_config(config))
+    }
+
+    fn from_config(config: PoolConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                slots:        Mutex::default(),
+                min_size:     config.min_size,
+                max_size:     config.max_size,
+                idle_timeout: config.idle_timeout,
+                ping_timeout: config.ping_timeout,
+            }),
+        }
+    }
+
+    /// Checks out a connection to the address, opening a new one if none
+    /// are idle.
+    pub async fn get(&self, address: impl ToString) -> Result<Pooled<In, Out>> {
+        let address = address.to_string();
+        let permit = self.permits(&address).acquire_owned().await?;
+
+        while let Some(client) = self.take_idle(&address) {
+            if self.responds(&client).await {
+                return Ok(self.pooled(address, client, permit));
+            }
+            debug!("Pool dropped unresponsive connection to {address}");
+        }
+
+        let client = Client::connect(address.as_str()).await?;
+
+        debug!("Pool opened connection to {address}");
+
+        Ok(self.pooled(address, client, permit))
+    }
+
+    /// Opens connections to the address until there are at least
+    /// `min_size` of them.
+    pub async fn warm_up(&self, address: impl ToString) -> Result<()> {
+        let address = address.to_string();
+        let permits = self.permits(&address);
+
+        while self.size(&address) < self.shared.min_size {
+            let Ok(permit) = permits.clone().try_acquire_owned() else {
+                break;
+            };
+            drop(self.pooled(address.clone(), Client::connect(address.as_str()).await?, permit));
+        }
+
+        Ok(())
+    }
+
+    pub fn idle(&self, address: impl ToString) -> usize {
+        self.shared
+            .slots
+            .lock()
+            .get(&address.to_string())
+            .map_or(0, |slot| slot.idle.len())
+    }
+
+    /// Idle and checked out connections to the address.
+    pub fn size(&self, address: impl ToString) -> usize {
+        self.shared.slots.lock().get(&address.to_string()).map_or(0, |slot| {
+            slot.idle.len() + self.shared.max_size - slot.permits.available_permits()
+        })
+    }
+
+    fn permits(&self, address: &str) -> Arc<Semaphore> {
+        let mut slots = self.shared.slots.lock();
+        self.shared.evict(&mut slots);
+
+        slots
+            .entry(address.to_string())
+            .or_insert_with(|| Slot {
+                idle:    VecDeque::new(),
+                permits: Arc::new(Semaphore::new(self.shared.max_size)),
+            })
+            .permits
+            .clone()
+    }
+
+    /// A connection can look open while the peer is gone, e.g. after a
+    /// network change, only a round trip tells.
+    async fn responds(&self, client: &Client<In, Out>) -> bool {
+        let Some(ping_timeout) = self.shared.ping_timeout else {
+            return true;
+        };
+        matches!(timeout(ping_timeout, client.ping()).await, Ok(Ok(_)))
+    }
+
+    fn take_idle(&self, address: &str) -> Option<Client<In, Out>> {
+        let mut slots = self.shared.slots.lock();
+        let slot = slots.get_mut(address)?;
+
+        while let Some(idle) = slot.idle.pop_back() {
+            if !reusable(&idle.client) {
+                debug!("Pool dropped connection to {address}");
+                continue;
+            }
+            return Some(idle.client);
+        }
+
+        None
+    }
+
+    fn pooled(
+        &self,
+        address: String,
+        client: Client<In, Out>,
+        permit: OwnedSemaphorePermit,
+    ) -> Pooled<In, Out> {
+        Pooled {
+            client: Some(client),
+            address,
+            shared: self.shared.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+impl<In: Decode, Out: Encode> Default for Pool<In, Out> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<In, Out> Clone for Pool<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<In, Out> std::fmt::Debug for Pool<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = type_name::<In>();
+        let o = type_name::<Out>();
+
+        f.debug_struct(&format!("Pool<{i}, {o}>"))
+            .field("min_size", &self.shared.min_size)
+            .field("max_size", &self.shared.max_size)
+            .field("idle_timeout", &self.shared.idle_timeout)
+            .field("ping_timeout", &self.shared.ping_timeout)
+            .finish()
+    }
+}
+
+/// Checked out [`Client`]. Goes back to the pool when dropped unless the
+/// connection is closed or has unread messages.
+pub struct Pooled<In: Decode, Out: Encode> {
+    client:  Option<Client<In, Out>>,
+    address: String,
+    shared:  Arc<Shared<In, Out>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<In: Decode, Out: Encode> Pooled<In, Out> {
+    /// Closes the connection instead of returning it to the pool, e.g. after
+    /// a request whose response hasn't arrived yet.
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl<In: Decode, Out: Encode> Deref for Pooled<In, Out> {
+    type Target = Client<In, Out>;
+
+    fn deref(&self) -> &Client<In, Out> {
+        self.client.as_ref().expect("Pooled client is taken")
+    }
+}
+
+impl<In: Decode, Out: Encode> Drop for Pooled<In, Out> {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        if !reusable(&client) {
+            debug!("Pool dropped connection to {}", self.address);
+            return;
+        }
+
+        let mut slots = self.shared.slots.lock();
+
+        if let Some(slot) = slots.get_mut(&self.address) {
+            slot.idle.push_back(Idle {
+                client,
+                since: Instant::now(),
+            });
+        }
+
+        self.shared.evict(&mut slots);
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<In: Decode, Out: Encode> std::fmt::Debug for Pooled<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pooled")
+            .field("address", &self.address)
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use hreads::log_spawn;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::{
+        net::TcpListener,
+        time::{sleep, timeout},
+    };
+
+    use crate::{Pool, PoolConfig, Retry, Server, Service};
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<u32, u32> for Echo {
+        async fn respond(&self, i: u32) -> Result<u32> {
+            Ok(i)
+        }
+    }
+
+    async fn echo(port: u16) -> Result<()> {
+        let server = Server::start(port).await?;
+        log_spawn(async move { server.serve(Echo).await });
+        sleep(Duration::from_millis(50)).await;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_pool_reuse_and_limit() -> Result<()> {
+        echo(57808).await?;
+
+        let address = "127.0.0.1:57808";
+        let pool = Pool::<u32, u32>::with_config(PoolConfig::default().max_size(2))?;
+
+        let local = {
+            let client = pool.get(address).await?;
+            client.send(5u32).await?;
+            assert_eq!(5, client.receive().await?);
+            client.local_addr().await?
+        };
+
+        assert_eq!(1, pool.idle(address));
+
+        let first = Retry::new().run(|| pool.get(address)).await?;
+        assert_eq!(local, first.local_addr().await?);
+
+        let second = pool.get(address).await?;
+        assert_eq!(2, pool.size(address));
+
+        assert!(timeout(Duration::from_millis(100), pool.get(address)).await.is_err());
+
+        second.discard();
+        assert_eq!(1, pool.size(address));
+
+        let third = pool.get(address).await?;
+        assert_ne!(local, third.local_addr().await?);
+
+        drop(first);
+        drop(third);
+        assert_eq!(2, pool.idle(address));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_pool_eviction() -> Result<()> {
+        echo(57809).await?;
+
+        let address = "127.0.0.1:57809";
+        let pool = Pool::<u32, u32>::with_config(
+            PoolConfig::default()
+                .min_size(1)
+                .max_size(4)
+                .idle_timeout(Duration::from_millis(50)),
+        )?;
+
+        pool.warm_up(address).await?;
+        assert_eq!(1, pool.idle(address));
+
+        {
+            let _a = pool.get(address).await?;
+            let _b = pool.get(address).await?;
+            let _c = pool.get(address).await?;
+        }
+
+        assert_eq!(3, pool.idle(address));
+
+        sleep(Duration::from_millis(100)).await;
+
+        let client = pool.get(address).await?;
+        client.send(1u32).await?;
+        assert_eq!(1, client.receive().await?);
+
+        assert_eq!(0, pool.idle(address));
+        assert_eq!(1, pool.size(address));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_pool_health_check() -> Result<()> {
+        let server = Server::<u32, u32>::start(57810).await?;
+
+        let address = "127.0.0.1:57810";
+        let pool = Pool::<u32, u32>::new();
+
+        let local = pool.get(address).await?.local_addr().await?;
+
+        drop(server.wait_for_new_connection().await);
+        sleep(Duration::from_millis(50)).await;
+
+        let (client, connection) = tokio::join!(pool.get(address), server.wait_for_new_connection());
+        let client = client?;
+
+        assert_ne!(local, client.local_addr().await?);
+
+        connection.send(7u32).await?;
+        assert_eq!(7, client.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_pool_unresponsive_peer() -> Result<()> {
+        // Accepts connections but never reads from them.
+        let listener = TcpListener::bind("127.0.0.1:57852").await?;
+
+        let address = "127.0.0.1:57852";
+        let pool = Pool::<u32, u32>::with_config(
+            PoolConfig::default().ping_timeout(Some(Duration::from_millis(100))),
+        )?;
+
+        let (client, accepted) = tokio::join!(pool.get(address), listener.accept());
+        let local = client?.local_addr().await?;
+        let _first = accepted?;
+
+        assert_eq!(1, pool.idle(address));
+
+        let (client, accepted) = tokio::join!(pool.get(address), listener.accept());
+        let _second = accepted?;
+
+        assert_ne!(local, client?.local_addr().await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_config() {
+        assert!(Pool::<u32, u32>::with_config(PoolConfig::default().min_size(10).max_size(20)).is_ok());
+        assert!(Pool::<u32, u32>::with_config(PoolConfig::default().min_size(10)).is_err());
+        assert!(Pool::<u32, u32>::with_config(PoolConfig::default().max_size(0)).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_pool_drops_unread() -> Result<()> {
+        echo(57838).await?;
+
+        let address = "127.0.0.1:57838";
+        let pool = Pool::<u32, u32>::new();
+
+        {
+            let client = pool.get(address).await?;
+            client.send(1u32).await?;
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(0, pool.idle(address));
+
+        let client = pool.get(address).await?;
+        client.send(2u32).await?;
+        assert_eq!(2, client.receive().await?);
+
+        Ok(())
+    }
+}
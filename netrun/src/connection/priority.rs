@@ -0,0 +1,74 @@
+/// Lane a message travels in. High priority messages overtake queued normal
+/// ones, both when written to the socket and when handed to the receiver.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub(crate) const fn index(self) -> usize {
+        match self {
+            Self::Normal => 0,
+            Self::High => 1,
+        }
+    }
+}
+
+/// How many high priority items may go in a row while normal ones are
+/// waiting, so a flood of high priority messages doesn't starve the rest.
+pub(crate) const MAX_STREAK: u32 = 8;
+
+/// Decides which lane goes next.
+#[derive(Debug, Default)]
+pub(crate) struct Fairness {
+    streak: u32,
+}
+
+impl Fairness {
+    /// Lanes in the order they should be checked.
+    pub fn order(&self) -> [Priority; 2] {
+        if self.streak >= MAX_STREAK {
+            [Priority::Normal, Priority::High]
+        } else {
+            [Priority::High, Priority::Normal]
+        }
+    }
+
+    pub fn served(&mut self, priority: Priority) {
+        match priority {
+            Priority::High => self.streak += 1,
+            Priority::Normal => self.streak = 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        Priority,
+        connection::priority::{Fairness, MAX_STREAK},
+    };
+
+    #[test]
+    fn test_fairness() {
+        let mut fairness = Fairness::default();
+        let mut order = vec![];
+
+        // Both lanes always have something waiting.
+        for _ in 0..2 * (MAX_STREAK + 1) {
+            let next = fairness.order()[0];
+            fairness.served(next);
+            order.push(next);
+        }
+
+        let mut expected = vec![Priority::High; MAX_STREAK as usize];
+        expected.push(Priority::Normal);
+        let expected = expected.repeat(2);
+
+        assert_eq!(expected, order);
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    any::type_name,
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, trace, warn};
+use parking_lot::Mutex as SyncMutex;
+use tokio::{
+    net::ToSocketAddrs,
+    select, spawn,
+    sync::{
+        Mutex,
+        mpsc::{Sender, channel, error::TrySendError},
+    },
+    try_join,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::{Bytes, Channel, Client, Message, Server},
+    serde::{Encoding, MAX_SENDERS, ReplayWindows},
+};
+
+const CONTROL_CHANNEL: &str = "netrun.pubsub";
+const DEFAULT_BUFFER: usize = 1024;
+
+const PUBLISH: u8 = 0;
+const RETAIN: u8 = 1;
+const MESSAGE: u8 = 2;
+const SUBSCRIBE: u8 = 3;
+const UNSUBSCRIBE: u8 = 4;
+const OK: u8 = 5;
+const ERROR: u8 = 6;
+const CLEAR: u8 = 7;
+
+/// `[tag: u8][topic length: u16][topic][payload]`
+struct Packet {
+    tag:     u8,
+    topic:   String,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(tag: u8, topic: impl ToString, payload: Vec<u8>) -> Self {
+        Self {
+            tag,
+            topic: topic.to_string(),
+            payload,
+        }
+    }
+
+    fn encode(self) -> Result<Bytes> {
+        let topic_len = u16::try_from(self.topic.len()).map_err(|_| anyhow!("Topic is too long"))?;
+
+        let mut data = Vec::with_capacity(3 + self.topic.len() + self.payload.len());
+        data.push(self.tag);
+        data.extend_from_slice(&topic_len.to_le_bytes());
+        data.extend_from_slice(self.topic.as_bytes());
+        data.extend_from_slice(&self.payload);
+        Ok(Bytes(data))
+    }
+
+    fn decode(bytes: Bytes) -> Result<Self> {
+        let data = bytes.into_inner();
+
+        let Some(header) = data.get(..3) else {
+            bail!("Pub/sub packet is too short: {}", data.len());
+        };
+
+        let topic_end = 3 + usize::from(u16::from_le_bytes([header[1], header[2]]));
+
+        let Some(topic) = data.get(3..topic_end) else {
+            bail!("Pub/sub packet topic is cut: {}", data.len());
+        };
+
+        Ok(Self {
+            tag:     header[0],
+            topic:   String::from_utf8(topic.to_vec())?,
+            payload: data[topic_end..].to_vec(),
+        })
+    }
+}
+
+/// Checks a subscription pattern. Topic levels are separated with `/`, `+`
+/// matches exactly one level and `#` matches any remaining levels.
+fn validate_pattern(pattern: &str) -> Result<()> {
+    let levels: Vec<_> = pattern.split('/').collect();
+
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+            bail!("Invalid topic pattern {pattern}: # must be the whole last level");
+        }
+        if level.contains('+') && *level != "+" {
+            bail!("Invalid topic pattern {pattern}: + must be a whole level");
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        bail!("Invalid topic: {topic:?}");
+    }
+    Ok(())
+}
+
+fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+
+    for level in pattern.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (level, Some(name)) if level == name => (),
+            _ => return false,
+        }
+    }
+
+    topic.next().is_none()
+}
+
+struct Subscriber {
+    id:       usize,
+    patterns: Vec<String>,
+    queue:    Sender<(String, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct Topics {
+    subscribers: SyncMutex<Vec<Subscriber>>,
+    retained:    SyncMutex<BTreeMap<String, Vec<u8>>>,
+    next_id:     AtomicUsize,
+    dropped:     AtomicU64,
+}
+
+impl Topics {
+    fn route(&self, subscribers: &[Subscriber], topic: &str, payload: &[u8]) {
+        for subscriber in subscribers {
+            if subscriber.patterns.iter().any(|pattern| matches(pattern, topic)) {
+                self.deliver(subscriber, topic, payload);
+            }
+        }
+    }
+
+    /// Messages for a subscriber whose buffer is full are dropped.
+    fn deliver(&self, subscriber: &Subscriber, topic: &str, payload: &[u8]) {
+        if let Err(TrySendError::Full(_)) = subscriber.queue.try_send((topic.to_string(), payload.to_vec())) {
+            trace!("Subscriber {} buffer is full. Dropping {topic}", subscriber.id);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Retained value is updated under the subscribers lock, so a client
+    /// subscribing meanwhile gets either the new value or the message.
+    fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) {
+        let subscribers = self.subscribers.lock();
+
+        self.route(&subscribers, topic, &payload);
+
+        if retain {
+            self.retained.lock().insert(topic.to_string(), payload);
+        }
+    }
+
+    fn clear(&self, topic: &str) {
+        self.retained.lock().remove(topic);
+    }
+
+    fn subscribe(&self, id: usize, pattern: String) {
+        let mut subscribers = self.subscribers.lock();
+        let Some(subscriber) = subscribers.iter_mut().find(|subscriber| subscriber.id == id) else {
+            return;
+        };
+
+        for (topic, payload) in self.retained.lock().iter() {
+            if matches(&pattern, topic) {
+                self.deliver(subscriber, topic, payload);
+            }
+        }
+
+        if !subscriber.patterns.contains(&pattern) {
+            subscriber.patterns.push(pattern);
+        }
+    }
+
+    fn unsubscribe(&self, id: usize, pattern: &str) {
+        if let Some(subscriber) = self.subscribers.lock().iter_mut().find(|subscriber| subscriber.id == id) {
+            subscriber.patterns.retain(|existing| existing != pattern);
+        }
+    }
+
+    async fn serve(&self, connection: Client<Bytes, Bytes>, buffer: usize) -> Result<()> {
+        let control: Channel<Bytes, Bytes> = connection.accept_channel(CONTROL_CHANNEL).await?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, mut outgoing) = channel(buffer);
+
+        self.subscribers.lock().push(Subscriber {
+            id,
+            patterns: vec![],
+            queue,
+        });
+
+        debug!("Pub/sub client {id} connected");
+
+        let delivery = async {
+            while let Some((topic, payload)) = outgoing.recv().await {
+                connection.send(Packet::new(MESSAGE, topic, payload).encode()?).await?;
+            }
+            anyhow::Ok(())
+        };
+
+        let result = try_join!(
+            self.commands(id, &control),
+            self.publications(id, &connection),
+            delivery
+        );
+
+        self.subscribers.lock().retain(|subscriber| subscriber.id != id);
+
+        debug!("Pub/sub client {id} disconnected");
+
+        result.map(|_| ())
+    }
+
+    async fn commands(&self, id: usize, control: &Channel<Bytes, Bytes>) -> Result<()> {
+        loop {
+            let command = Packet::decode(control.receive().await?)?;
+
+            let reply = match command.tag {
+                SUBSCRIBE => validate_pattern(&command.topic).map(|()| self.subscribe(id, command.topic)),
+                UNSUBSCRIBE => {
+                    self.unsubscribe(id, &command.topic);
+                    Ok(())
+                }
+                tag => Err(anyhow!("Unknown pub/sub command: {tag}")),
+            };
+
+            let reply = match reply {
+                Ok(()) => Packet::new(OK, "", vec![]),
+                Err(err) => Packet::new(ERROR, "", err.to_string().into_bytes()),
+            };
+
+            control.send(reply.encode()?).await?;
+        }
+    }
+
+    async fn publications(&self, id: usize, connection: &Client<Bytes, Bytes>) -> Result<()> {
+        loop {
+            let packet = Packet::decode(connection.receive().await?)?;
+
+            match packet.tag {
+                PUBLISH | RETAIN | CLEAR if validate_topic(&packet.topic).is_err() => {
+                    warn!("Pub/sub client {id} published to invalid topic {}", packet.topic);
+                }
+                PUBLISH | RETAIN => self.publish(&packet.topic, packet.payload, packet.tag == RETAIN),
+                CLEAR => self.clear(&packet.topic),
+                tag => warn!("Unexpected pub/sub packet: {tag}"),
+            }
+        }
+    }
+}
+
+/// Routes messages published by [`PubSub`] clients to the subscribers of
+/// their topics.
+///
+/// Every subscriber has a buffer of undelivered messages. When a slow
+/// subscriber fills it, new messages for it are dropped.
+pub struct Broker {
+    topics: Arc<Topics>,
+    cancel: CancellationToken,
+    port:   u16,
+}
+
+impl Broker {
+    pub async fn start(port: u16) -> Result<Self> {
+        Self::start_with_buffer(port, DEFAULT_BUFFER).await
+    }
+
+    pub async fn start_with_buffer(port: u16, buffer: usize) -> Result<Self> {
+        if buffer == 0 {
+            bail!("Broker buffer can't be 0");
+        }
+
+        let server = Server::<Bytes, Bytes>::start(port).await?;
+        let topics = Arc::new(Topics::default());
+        let cancel = CancellationToken::new();
+
+        let tp = topics.clone();
+        let cn = cancel.clone();
+
+        spawn(async move {
+            loop {
+                let connection = select! {
+                    () = cn.cancelled() => break,
+                    connection = server.wait_for_new_connection() => connection,
+                };
+
+                let tp = tp.clone();
+                let cn = cn.clone();
+
+                spawn(async move {
+                    select! {
+                        () = cn.cancelled() => (),
+                        result = tp.serve(connection, buffer) => {
+                            _ = result.inspect_err(|err| debug!("Pub/sub client closed: {err}"));
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { topics, cancel, port })
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.topics.subscribers.lock().len()
+    }
+
+    /// Messages dropped because subscriber buffers were full.
+    pub fn dropped(&self) -> u64 {
+        self.topics.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl std::fmt::Debug for Broker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Broker").field("port", &self.port).finish()
+    }
+}
+
+/// Connection to a [`Broker`] which publishes and receives `T` messages.
+pub struct PubSub<T> {
+    client:   Client<Bytes, Bytes>,
+    control:  Mutex<Channel<Bytes, Bytes>>,
+    encoding: SyncMutex<Encoding>,
+    /// The broker forwards the same encrypted bytes to every subscriber,
+    /// so each one checks for replays on its own.
+    replays:  Arc<ReplayWindows>,
+    _p:       PhantomData<Mutex<T>>,
+}
+
+impl<T: Message> PubSub<T> {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let client = Client::connect(addr).await?;
+        let control = client.open_channel(CONTROL_CHANNEL).await?;
+
+        Ok(Self {
+            client,
+            control: Mutex::new(control),
+            encoding: SyncMutex::default(),
+            replays: Arc::new(ReplayWindows::new(MAX_SENDERS)),
+            _p: PhantomData,
+        })
+    }
+
+    /// Codec of published values. Received ones are decoded by their
+    /// [`Header`](crate::serde::Header).
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.lock() = encoding;
+    }
+
+    /// Returns once the broker routes matching messages to this client.
+    /// Retained values of matching topics are delivered right away.
+    pub async fn subscribe(&self, pattern: impl ToString) -> Result<()> {
+        let pattern = pattern.to_string();
+        validate_pattern(&pattern)?;
+        self.command(SUBSCRIBE, pattern).await
+    }
+
+    pub async fn unsubscribe(&self, pattern: impl ToString) -> Result<()> {
+        self.command(UNSUBSCRIBE, pattern.to_string()).await
+    }
+
+    pub async fn publish(&self, topic: impl ToString, val: impl Into<T>) -> Result<()> {
+        self.send(PUBLISH, topic.to_string(), val.into()).await
+    }
+
+    /// Publishes and keeps the value on the broker for subscribers which
+    /// join later.
+    pub async fn publish_retained(&self, topic: impl ToString, val: impl Into<T>) -> Result<()> {
+        self.send(RETAIN, topic.to_string(), val.into()).await
+    }
+
+    /// Removes the retained value of the topic.
+    pub async fn clear_retained(&self, topic: impl ToString) -> Result<()> {
+        let topic = topic.to_string();
+        validate_topic(&topic)?;
+        self.client.send(Packet::new(CLEAR, topic, vec![]).encode()?).await
+    }
+
+    /// Next message from subscribed topics with the topic it was published to.
+    pub async fn receive(&self) -> Result<(String, T)> {
+        let packet = Packet::decode(self.client.receive().await?)?;
+
+        if packet.tag != MESSAGE {
+            bail!("Unexpected pub/sub packet: {}", packet.tag);
+        }
+
+        let encoding = *self.encoding.lock();
+        let val = self.replays.check(|| T::decode_with(packet.payload, encoding))?;
+
+        Ok((packet.topic, val))
+    }
+
+    async fn send(&self, tag: u8, topic: String, val: T) -> Result<()> {
+        validate_topic(&topic)?;
+        let payload = val.encode_with(*self.encoding.lock())?;
+        self.client.send(Packet::new(tag, topic, payload).encode()?).await
+    }
+
+    async fn command(&self, tag: u8, pattern: String) -> Result<()> {
+        let control = self.control.lock().await;
+
+        control.send(Packet::new(tag, pattern, vec![]).encode()?).await?;
+
+        let reply = Packet::decode(control.receive().await?)?;
+
+        match reply.tag {
+            OK => Ok(()),
+            ERROR => bail!("{}", String::from_utf8_lossy(&reply.payload)),
+            tag => bail!("Unexpected pub/sub reply: {tag}"),
+        }
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> std::fmt::Debug for PubSub<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct(&format!("PubSub<{}>", type_name::<T>()))
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::time::sleep;
+
+    use crate::{
+        Broker, Bytes, PubSub,
+        connection::pubsub::{matches, validate_pattern},
+        serde::{Encoding, Format, Key},
+    };
+
+    #[test]
+    fn test_topic_patterns() {
+        assert!(matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!matches("sensors/+/temp", "sensors/kitchen/humidity"));
+        assert!(!matches("sensors/+/temp", "sensors/a/b/temp"));
+        assert!(matches("sensors/#", "sensors/a/b/temp"));
+        assert!(matches("sensors/#", "sensors"));
+        assert!(matches("#", "anything/at/all"));
+        assert!(!matches("sensors", "sensors/kitchen"));
+        assert!(matches("sensors", "sensors"));
+
+        assert!(validate_pattern("a/+/b/#").is_ok());
+        assert!(validate_pattern("a/#/b").is_err());
+        assert!(validate_pattern("a/b+").is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_pubsub() -> Result<()> {
+        let broker = Broker::start(57821).await?;
+
+        let kitchen = PubSub::<f32>::connect((Ipv4Addr::LOCALHOST, 57821)).await?;
+        let all = PubSub::<f32>::connect((Ipv4Addr::LOCALHOST, 57821)).await?;
+        let publisher = PubSub::<f32>::connect((Ipv4Addr::LOCALHOST, 57821)).await?;
+
+        kitchen.subscribe("home/kitchen/+").await?;
+        all.subscribe("home/#").await?;
+
+        assert!(kitchen.subscribe("home/#/temp").await.is_err());
+        assert!(publisher.publish("home/+", 1.0).await.is_err());
+
+        publisher.publish("home/kitchen/temp", 21.5).await?;
+        publisher.publish("home/garage/temp", 10.0).await?;
+
+        assert_eq!(("home/kitchen/temp".to_string(), 21.5), kitchen.receive().await?);
+        assert_eq!(("home/kitchen/temp".to_string(), 21.5), all.receive().await?);
+        assert_eq!(("home/garage/temp".to_string(), 10.0), all.receive().await?);
+
+        kitchen.unsubscribe("home/kitchen/+").await?;
+        publisher.publish("home/kitchen/temp", 22.0).await?;
+        assert_eq!(("home/kitchen/temp".to_string(), 22.0), all.receive().await?);
+        assert!(kitchen.client.receive_timeout(Duration::from_millis(50)).await.is_err());
+
+        assert_eq!(3, broker.subscribers());
+
+        drop(kitchen);
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(2, broker.subscribers());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_retained_and_buffer_limit() -> Result<()> {
+        let broker = Broker::start_with_buffer(57822, 4).await?;
+
+        let publisher = PubSub::<String>::connect((Ipv4Addr::LOCALHOST, 57822)).await?;
+
+        publisher.publish_retained("status/a", "up").await?;
+        publisher.publish_retained("status/b", "down").await?;
+        publisher.publish_retained("status/b", "up").await?;
+        publisher.publish("status/c", "not retained").await?;
+        sleep(Duration::from_millis(50)).await;
+
+        let late = PubSub::<String>::connect((Ipv4Addr::LOCALHOST, 57822)).await?;
+        late.subscribe("status/+").await?;
+
+        assert_eq!(("status/a".to_string(), "up".to_string()), late.receive().await?);
+        assert_eq!(("status/b".to_string(), "up".to_string()), late.receive().await?);
+
+        publisher.clear_retained("status/a").await?;
+        sleep(Duration::from_millis(50)).await;
+
+        let later = PubSub::<String>::connect((Ipv4Addr::LOCALHOST, 57822)).await?;
+        later.subscribe("status/#").await?;
+        assert_eq!(("status/b".to_string(), "up".to_string()), later.receive().await?);
+
+        let slow = PubSub::<String>::connect((Ipv4Addr::LOCALHOST, 57822)).await?;
+        slow.subscribe("flood").await?;
+
+        let big = "x".repeat(64 * 1024);
+        for _ in 0..200 {
+            publisher.publish("flood", big.as_str()).await?;
+        }
+
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(broker.dropped() > 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_encrypted_pubsub() -> Result<()> {
+        let _broker = Broker::start(57850).await?;
+
+        let encoding = Encoding::compressed(Format::Json).encrypt(&Key::generate(70_030));
+
+        let first = PubSub::<i32>::connect((Ipv4Addr::LOCALHOST, 57850)).await?;
+        let second = PubSub::<i32>::connect((Ipv4Addr::LOCALHOST, 57850)).await?;
+        let publisher = PubSub::<i32>::connect((Ipv4Addr::LOCALHOST, 57850)).await?;
+
+        for pubsub in [&first, &second, &publisher] {
+            pubsub.set_encoding(encoding);
+        }
+
+        first.subscribe("counter").await?;
+        second.subscribe("counter").await?;
+
+        for i in 0..100 {
+            publisher.publish("counter", i).await?;
+        }
+
+        // Both get the same encrypted bytes.
+        for i in 0..100 {
+            assert_eq!(("counter".to_string(), i), first.receive().await?);
+        }
+        for i in 0..100 {
+            assert_eq!(("counter".to_string(), i), second.receive().await?);
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_empty_retained_value() -> Result<()> {
+        assert!(Broker::start_with_buffer(57842, 0).await.is_err());
+
+        let _broker = Broker::start(57842).await?;
+
+        let publisher = PubSub::<Bytes>::connect((Ipv4Addr::LOCALHOST, 57842)).await?;
+        publisher.publish_retained("empty", Bytes(vec![])).await?;
+        sleep(Duration::from_millis(50)).await;
+
+        let late = PubSub::<Bytes>::connect((Ipv4Addr::LOCALHOST, 57842)).await?;
+        late.subscribe("empty").await?;
+        assert_eq!(("empty".to_string(), Bytes(vec![])), late.receive().await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt::{Display, Write},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow, bail};
+use log::{LevelFilter, Log, Metadata, Record, debug, error};
+use parking_lot::{Condvar, Mutex as SyncMutex};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, stdout},
+    runtime::Builder,
+    select, spawn,
+    sync::{Mutex, Notify},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    System,
+    connection::{Client, Server},
+};
+
+const DEFAULT_CAPACITY: usize = 10_000;
+const BATCH: usize = 256;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    /// Set on the shipping thread. Records logged while shipping are skipped,
+    /// otherwise every sent batch would produce new records to send.
+    static SHIPPING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Log record as it travels from [`RemoteLogger`] to [`LogCollector`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub level:     String,
+    pub target:    String,
+    pub message:   String,
+    pub hostname:  String,
+    pub instance:  String,
+}
+
+impl Display for LogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{:03} {} {} {:<5} {}: {}",
+            self.timestamp / 1000,
+            self.timestamp % 1000,
+            self.hostname,
+            self.instance,
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    records:   VecDeque<LogRecord>,
+    in_flight: usize,
+}
+
+struct Shared {
+    queue:    SyncMutex<Queue>,
+    shipped:  Condvar,
+    notify:   Notify,
+    capacity: usize,
+    dropped:  AtomicU64,
+}
+
+impl Shared {
+    /// Oldest records are dropped when the queue is full.
+    fn push(&self, queue: &mut Queue, record: LogRecord) {
+        if queue.records.len() >= self.capacity {
+            queue.records.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.records.push_back(record);
+    }
+
+    async fn ship(&self, address: &str) {
+        loop {
+            match Client::<(), Vec<LogRecord>>::connect(address).await {
+                Ok(client) => {
+                    debug!("Connected to log collector: {address}");
+                    _ = self
+                        .send_batches(&client)
+                        .await
+                        .inspect_err(|err| debug!("Log collector connection lost: {err}"));
+                }
+                Err(err) => debug!("Failed to connect to log collector {address}: {err}"),
+            }
+
+            sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    async fn send_batches(&self, client: &Client<(), Vec<LogRecord>>) -> Result<()> {
+        loop {
+            let batch: Vec<_> = {
+                let mut queue = self.queue.lock();
+                let len = queue.records.len().min(BATCH);
+                queue.in_flight = len;
+                queue.records.drain(..len).collect()
+            };
+
+            if batch.is_empty() {
+                select! {
+                    () = self.notify.notified() => (),
+                    () = sleep(FLUSH_INTERVAL) => (),
+                }
+                continue;
+            }
+
+            let result = client.send(batch.clone()).await;
+
+            let mut queue = self.queue.lock();
+            queue.in_flight = 0;
+
+            if result.is_err() {
+                for record in batch.into_iter().rev() {
+                    if queue.records.len() >= self.capacity {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        queue.records.push_front(record);
+                    }
+                }
+            }
+
+            self.shipped.notify_all();
+
+            result?;
+        }
+    }
+}
+
+/// [`log::Log`] implementation which ships records to a [`LogCollector`].
+///
+/// Records are sent in batches from a background thread. While the collector
+/// is unreachable they wait in a bounded queue, and the oldest ones are
+/// dropped when it overflows.
+pub struct RemoteLogger {
+    shared:   Arc<Shared>,
+    cancel:   CancellationToken,
+    level:    LevelFilter,
+    hostname: String,
+    instance: String,
+}
+
+impl RemoteLogger {
+    pub fn new(address: impl ToString, level: LevelFilter) -> Result<Self> {
+        Self::with_capacity(address, level, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(address: impl ToString, level: LevelFilter, capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            bail!("Log queue capacity can't be 0");
+        }
+
+        let shared = Arc::new(Shared {
+            queue: SyncMutex::default(),
+            shipped: Condvar::new(),
+            notify: Notify::new(),
+            capacity,
+            dropped: AtomicU64::default(),
+        });
+
+        let cancel = CancellationToken::new();
+
+        let sh = shared.clone();
+        let cn = cancel.clone();
+        let address = address.to_string();
+
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+
+        thread::Builder::new().name("netrun-log".to_string()).spawn(move || {
+            SHIPPING.set(true);
+            runtime.block_on(async {
+                select! {
+                    () = cn.cancelled() => (),
+                    () = sh.ship(&address) => (),
+                }
+            });
+        })?;
+
+        Ok(Self {
+            shared,
+            cancel,
+            level,
+            hostname: System::hostname(),
+            instance: System::generate_app_instance_id(),
+        })
+    }
+
+    /// Installs the logger as the global [`log`] logger.
+    pub fn init(self) -> Result<()> {
+        let level = self.level;
+        log::set_logger(Box::leak(Box::new(self))).map_err(|err| anyhow!("{err}"))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// Records waiting to be shipped.
+    pub fn pending(&self) -> usize {
+        let queue = self.shared.queue.lock();
+        queue.records.len() + queue.in_flight
+    }
+
+    /// Records dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Log for RemoteLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && !SHIPPING.get()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| u64::try_from(since.as_millis()).unwrap_or(u64::MAX))
+            .unwrap_or_default();
+
+        let record = LogRecord {
+            timestamp,
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            hostname: self.hostname.clone(),
+            instance: self.instance.clone(),
+        };
+
+        self.shared.push(&mut self.shared.queue.lock(), record);
+        self.shared.notify.notify_one();
+    }
+
+    /// Waits up to a second for queued records to be shipped.
+    fn flush(&self) {
+        if SHIPPING.get() {
+            return;
+        }
+
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+
+        self.shared.notify.notify_one();
+
+        let mut queue = self.shared.queue.lock();
+
+        while !queue.records.is_empty() || queue.in_flight > 0 {
+            if self.shared.shipped.wait_until(&mut queue, deadline).timed_out() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for RemoteLogger {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl std::fmt::Debug for RemoteLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteLogger")
+            .field("level", &self.level)
+            .field("hostname", &self.hostname)
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+/// Where [`LogCollector`] writes received records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout,
+    /// Records are appended to the file.
+    File(PathBuf),
+}
+
+type Output = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+/// Receives records from [`RemoteLogger`]s and writes them one per line.
+pub struct LogCollector {
+    cancel: CancellationToken,
+    port:   u16,
+}
+
+impl LogCollector {
+    pub async fn start(port: u16, output: LogOutput) -> Result<Self> {
+        let output: Box<dyn AsyncWrite + Send + Unpin> = match output {
+            LogOutput::Stdout => Box::new(stdout()),
+            LogOutput::File(path) => Box::new(OpenOptions::new().create(true).append(true).open(path).await?),
+        };
+        let output: Output = Arc::new(Mutex::new(output));
+
+        let server = Server::<Vec<LogRecord>, ()>::start(port).await?;
+        let cancel = CancellationToken::new();
+
+        let cn = cancel.clone();
+
+        spawn(async move {
+            loop {
+                let connection = select! {
+                    () = cn.cancelled() => break,
+                    connection = server.wait_for_new_connection() => connection,
+                };
+
+                let output = output.clone();
+                let cn = cn.clone();
+
+                spawn(async move {
+                    select! {
+                        () = cn.cancelled() => (),
+                        result = Self::collect(&connection, &output) => {
+                            _ = result.inspect_err(|err| debug!("Log shipper disconnected: {err}"));
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { cancel, port })
+    }
+
+    async fn collect(connection: &Client<Vec<LogRecord>, ()>, output: &Output) -> Result<()> {
+        loop {
+            let batch = connection.receive().await?;
+
+            let mut lines = String::new();
+            for record in &batch {
+                _ = writeln!(lines, "{record}");
+            }
+
+            let mut output = output.lock().await;
+            _ = output
+                .write_all(lines.as_bytes())
+                .await
+                .inspect_err(|err| error!("Failed to write log records: {err}"));
+            output.flush().await?;
+        }
+    }
+}
+
+impl Drop for LogCollector {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl std::fmt::Debug for LogCollector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogCollector").field("port", &self.port).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use log::{Level, LevelFilter, Log, Record};
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::time::sleep;
+
+    use crate::{LogCollector, LogOutput, RemoteLogger, System};
+
+    fn log(logger: &RemoteLogger, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target("remote_log_test")
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_remote_log() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("netrun-{}.log", System::generate_app_instance_id()));
+
+        let logger = RemoteLogger::new("127.0.0.1:57823", LevelFilter::Info)?;
+
+        log(&logger, Level::Info, "before collector");
+        log(&logger, Level::Debug, "filtered");
+        log(&logger, Level::Warn, "still before collector");
+
+        assert_eq!(2, logger.pending());
+
+        let _collector = LogCollector::start(57823, LogOutput::File(path.clone())).await?;
+
+        log(&logger, Level::Error, "after collector");
+
+        let mut lines = vec![];
+
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            lines = std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(ToString::to_string)
+                .collect();
+            if lines.len() == 3 {
+                break;
+            }
+        }
+
+        assert_eq!(3, lines.len(), "{lines:?}");
+        assert_eq!(0, logger.pending());
+
+        for (line, (level, message)) in lines.iter().zip([
+            ("INFO ", "before collector"),
+            ("WARN ", "still before collector"),
+            ("ERROR", "after collector"),
+        ]) {
+            assert!(line.contains(&System::hostname()), "{line}");
+            assert!(line.contains(logger.instance()), "{line}");
+            assert!(
+                line.ends_with(&format!("{level} remote_log_test: {message}")),
+                "{line}"
+            );
+        }
+
+        _ = std::fs::remove_file(path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_log_queue_limit() -> Result<()> {
+        assert!(RemoteLogger::with_capacity("127.0.0.1:57824", LevelFilter::Trace, 0).is_err());
+
+        let logger = RemoteLogger::with_capacity("127.0.0.1:57824", LevelFilter::Trace, 2)?;
+
+        for i in 0..5 {
+            log(&logger, Level::Info, &i.to_string());
+        }
+
+        assert_eq!(2, logger.pending());
+        assert_eq!(3, logger.dropped());
+
+        Ok(())
+    }
+}
@@ -2,12 +2,12 @@ use std::{
     any::type_name,
     marker::PhantomData,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
 };
 
 use anyhow::Result;
 use hreads::log_spawn;
-use log::{debug, error, trace};
-use serde::{Serialize, de::DeserializeOwned};
+use log::{debug, error, trace, warn};
 use tokio::{
     net::{TcpListener, TcpStream},
     select, spawn,
@@ -18,20 +18,29 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::{Service, System, connection::Client};
+use crate::{
+    Decode, Encode, Service, System,
+    connection::{
+        Client, ServerConfig, ServerMetrics,
+        accept::{Admission, Gate},
+    },
+};
 
 pub struct Server<In, Out> {
     cancel:    CancellationToken,
     connected: Mutex<Receiver<Client<In, Out>>>,
+    gate:      Arc<Gate>,
     port:      u16,
     pub id:    String,
     _p:        PhantomData<Mutex<(In, Out)>>,
 }
 
-impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + DeserializeOwned + Send + 'static>
-    Server<In, Out>
-{
+impl<In: Decode, Out: Encode> Server<In, Out> {
     pub async fn start(port: u16) -> Result<Self> {
+        Self::start_with_config(port, ServerConfig::default()).await
+    }
+
+    pub async fn start_with_config(port: u16, config: ServerConfig) -> Result<Self> {
         let id = System::generate_app_instance_id();
         let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?;
 
@@ -39,6 +48,9 @@ impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + Deseria
 
         let cn = cancel.clone();
 
+        let gate = Gate::new(config);
+        let gt = gate.clone();
+
         let (s, r) = channel(1);
 
         spawn(async move {
@@ -50,7 +62,10 @@ impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + Deseria
                     }
                     connection = listener.accept() => {
                         match connection {
-                            Ok((stream, _)) => Self::add_connection(&s, stream).await,
+                            Ok((stream, peer)) => match gt.admit(peer.ip()) {
+                                Ok(admission) => Self::add_connection(&s, stream, admission).await,
+                                Err(rejection) => warn!("Rejected connection from {peer}: {rejection}"),
+                            },
                             Err(err) => error!("Failed to accept connection: {err}"),
                         }
                     }
@@ -61,12 +76,15 @@ impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + Deseria
         Ok(Self {
             cancel,
             connected: Mutex::new(r),
+            gate,
             port,
             id,
             _p: PhantomData,
         })
     }
 
+    /// High priority requests are handled ahead of queued normal ones and
+    /// answered in the same lane.
     pub async fn serve(&self, service: impl Service<In, Out> + Clone + Send + 'static) -> Result<()> {
         loop {
             let connection = self.wait_for_new_connection().await;
@@ -74,10 +92,10 @@ impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + Deseria
             let ser = service.clone();
             log_spawn::<anyhow::Error>(async move {
                 loop {
-                    let msg = connection.receive().await?;
+                    let (msg, priority) = connection.receive_with_priority().await?;
                     match ser.respond(msg).await {
                         Ok(response) => {
-                            connection.send(response).await?;
+                            connection.send_with_priority(response, priority).await?;
                         }
                         Err(err) => {
                             error!("Server failed to respond: {err}");
@@ -92,10 +110,18 @@ impl<In: Serialize + DeserializeOwned + Send + 'static, Out: Serialize + Deseria
         self.connected.lock().await.recv().await.expect("Dropped server")
     }
 
-    async fn add_connection(new_connection: &Sender<Client<In, Out>>, stream: TcpStream) {
+    pub fn metrics(&self) -> ServerMetrics {
+        self.gate.metrics()
+    }
+
+    async fn add_connection(
+        new_connection: &Sender<Client<In, Out>>,
+        stream: TcpStream,
+        admission: Admission,
+    ) {
         trace!("New connection");
 
-        let connection = Client::from_stream(stream);
+        let connection = Client::accepted(stream, admission);
 
         if let Err(err) = new_connection.send(connection).await {
             error!("Failed to send connection signal: {err}");
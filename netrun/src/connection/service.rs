@@ -1,10 +1,8 @@
 use anyhow::Result;
-use serde::{Serialize, de::DeserializeOwned};
 
-pub trait Service<
-    In: Serialize + DeserializeOwned + Send + 'static,
-    Out: Serialize + DeserializeOwned + Send + 'static,
-> {
+use crate::{Decode, Encode};
+
+pub trait Service<In: Decode, Out: Encode> {
     fn respond(&self, i: In) -> impl std::future::Future<Output = Result<Out>> + Send;
 }
 
@@ -0,0 +1,708 @@
+use std::{
+    any::type_name,
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use log::{debug, error, warn};
+use parking_lot::Mutex as SyncMutex;
+use tokio::{
+    select, spawn,
+    sync::{
+        Mutex, Semaphore,
+        mpsc::{Receiver, Sender, channel},
+    },
+    time::sleep,
+    try_join,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    System,
+    connection::{Bytes, Client, Decode, Encode, Server, link::WINDOW},
+    serde::{MAX_STREAM_SENDERS, ReplayWindows},
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+/// Messages kept for resending. [`Session::send`] waits while this many are
+/// unacknowledged.
+pub const MAX_UNACKED: usize = 1024;
+
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+const DATA: u8 = 2;
+const ACK: u8 = 3;
+
+enum Packet {
+    /// `token` is empty for a new session.
+    Hello {
+        session:  String,
+        received: u64,
+        token:    String,
+    },
+    /// `token` has to be presented to resume the session.
+    Welcome {
+        received: u64,
+        token:    String,
+    },
+    Data {
+        seq:     u64,
+        payload: Vec<u8>,
+    },
+    Ack {
+        seq: u64,
+    },
+}
+
+impl Packet {
+    fn encode(self) -> Bytes {
+        let (tag, number, tail) = match self {
+            Self::Hello {
+                session,
+                received,
+                token,
+            } => (HELLO, received, format!("{session}\n{token}").into_bytes()),
+            Self::Welcome { received, token } => (WELCOME, received, token.into_bytes()),
+            Self::Data { seq, payload } => (DATA, seq, payload),
+            Self::Ack { seq } => (ACK, seq, vec![]),
+        };
+
+        let mut data = Vec::with_capacity(9 + tail.len());
+        data.push(tag);
+        data.extend_from_slice(&number.to_le_bytes());
+        data.extend_from_slice(&tail);
+        Bytes(data)
+    }
+
+    fn decode(bytes: Bytes) -> Result<Self> {
+        let mut data = bytes.into_inner();
+
+        if data.len() < 9 {
+            bail!("Session packet is too short: {}", data.len());
+        }
+
+        let tag = data[0];
+        let number = u64::from_le_bytes(data[1..9].try_into()?);
+        data.drain(..9);
+
+        Ok(match tag {
+            HELLO => {
+                let hello = String::from_utf8(data)?;
+                let Some((session, token)) = hello.split_once('\n') else {
+                    bail!("Session hello without token");
+                };
+                Self::Hello {
+                    session:  session.to_string(),
+                    received: number,
+                    token:    token.to_string(),
+                }
+            }
+            WELCOME => Self::Welcome {
+                received: number,
+                token:    String::from_utf8(data)?,
+            },
+            DATA => Self::Data {
+                seq:     number,
+                payload: data,
+            },
+            ACK => Self::Ack { seq: number },
+            _ => bail!("Unknown session packet: {tag}"),
+        })
+    }
+}
+
+type Connection = Arc<Client<Bytes, Bytes>>;
+
+/// Connection the session currently sends over. Cancelling `broken` makes
+/// [`Core::run`] drop it, so the peer reconnects and everything
+/// unacknowledged is resent.
+#[derive(Clone)]
+struct Live {
+    connection: Connection,
+    broken:     CancellationToken,
+}
+
+#[derive(Default)]
+struct State {
+    next_seq: u64,
+    received: u64,
+    unacked:  VecDeque<(u64, Vec<u8>)>,
+    current:  Option<Live>,
+}
+
+/// Session state which outlives the connections it is carried over.
+///
+/// `writer` keeps sequence numbers in order on the wire and is the only lock
+/// held across network calls.
+struct Core {
+    id:       String,
+    /// Issued by the server, proves a reconnect belongs to the session.
+    token:    SyncMutex<String>,
+    state:    SyncMutex<State>,
+    writer:   Mutex<()>,
+    capacity: Semaphore,
+    inbox:    Sender<Vec<u8>>,
+}
+
+impl Core {
+    fn new(id: String, token: String) -> (Arc<Self>, Receiver<Vec<u8>>) {
+        let (inbox, receiver) = channel(WINDOW as usize);
+
+        let core = Arc::new(Self {
+            id,
+            token: SyncMutex::new(token),
+            state: SyncMutex::new(State {
+                next_seq: 1,
+                ..Default::default()
+            }),
+            writer: Mutex::new(()),
+            capacity: Semaphore::new(MAX_UNACKED),
+            inbox,
+        });
+
+        (core, receiver)
+    }
+
+    async fn send(&self, payload: Vec<u8>) -> Result<()> {
+        let permit = self.capacity.acquire().await?;
+
+        let _writer = self.writer.lock().await;
+
+        // The permit is given back by `acknowledge` from now on. Until then
+        // a cancelled send returns it.
+        let (seq, current) = {
+            let mut state = self.state.lock();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.unacked.push_back((seq, payload.clone()));
+            permit.forget();
+            (seq, state.current.clone())
+        };
+
+        // Unacknowledged messages are resent after reconnect anyway. A send
+        // which failed or was cancelled halfway leaves a gap in the sequence,
+        // so the connection can't be used any further.
+        if let Some(live) = current.filter(|live| !live.broken.is_cancelled()) {
+            let broken = live.broken.clone().drop_guard();
+
+            match live.connection.send(Packet::Data { seq, payload }.encode()).await {
+                Ok(()) => _ = broken.disarm(),
+                Err(err) => debug!("Session {} send deferred: {err}", self.id),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn received(&self) -> u64 {
+        self.state.lock().received
+    }
+
+    /// Forgets messages up to `seq` and makes room for new ones.
+    fn acknowledge(&self, seq: u64) {
+        let mut state = self.state.lock();
+        let before = state.unacked.len();
+        state.unacked.retain(|(s, _)| *s > seq);
+        self.capacity.add_permits(before - state.unacked.len());
+    }
+
+    /// Drops everything the peer already has and resends the rest.
+    async fn attach(&self, live: &Live, peer_received: u64) -> Result<()> {
+        let _writer = self.writer.lock().await;
+
+        self.acknowledge(peer_received);
+
+        let unacked: Vec<_> = self.state.lock().unacked.iter().cloned().collect();
+
+        for (seq, payload) in unacked {
+            live.connection.send(Packet::Data { seq, payload }.encode()).await?;
+        }
+
+        self.state.lock().current = Some(live.clone());
+
+        Ok(())
+    }
+
+    fn detach(&self, live: &Live) {
+        let mut state = self.state.lock();
+
+        if state
+            .current
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(&current.connection, &live.connection))
+        {
+            state.current = None;
+        }
+    }
+
+    /// Resends what the peer is missing and handles incoming packets until
+    /// the connection breaks. Both run at once so two peers resending to
+    /// each other keep granting credit.
+    async fn run(&self, connection: Connection, peer_received: u64) {
+        let live = Live {
+            connection,
+            broken: CancellationToken::new(),
+        };
+
+        select! {
+            () = live.broken.cancelled() => debug!("Session {} connection broken by failed send", self.id),
+            result = async { try_join!(self.attach(&live, peer_received), self.process(&live.connection)) } => {
+                if let Err(err) = result {
+                    debug!("Session {} connection lost: {err}", self.id);
+                }
+            }
+        }
+
+        self.detach(&live);
+    }
+
+    async fn process(&self, connection: &Connection) -> Result<()> {
+        loop {
+            match Packet::decode(connection.receive().await?)? {
+                Packet::Data { seq, payload } => {
+                    let slot = self.inbox.reserve().await.map_err(|_| anyhow!("Session dropped"))?;
+
+                    // A connection being replaced may still be processing,
+                    // so the sequence is claimed and delivered without
+                    // awaiting in between.
+                    let expected = {
+                        let mut state = self.state.lock();
+                        let expected = state.received + 1;
+                        if seq == expected {
+                            state.received = seq;
+                        }
+                        expected
+                    };
+
+                    // Only possible if the peer's send failed halfway, a
+                    // reconnect makes it resend.
+                    if seq > expected {
+                        bail!("Session {} sequence gap: expected {expected}, got {seq}", self.id);
+                    }
+
+                    if seq == expected {
+                        slot.send(payload);
+                    }
+
+                    connection.send(Packet::Ack { seq: self.received() }.encode()).await?;
+                }
+                Packet::Ack { seq } => self.acknowledge(seq),
+                Packet::Hello { .. } | Packet::Welcome { .. } => {
+                    warn!("Unexpected handshake in session {}", self.id);
+                }
+            }
+        }
+    }
+}
+
+/// Reliable typed connection which survives reconnects.
+///
+/// Every message gets a sequence number and stays in memory until the peer
+/// acknowledges it. After a reconnect both sides resend what the other one
+/// hasn't acknowledged and drop duplicates, so every message is delivered
+/// exactly once as long as the session is alive. Reconnects present a token
+/// issued by the server, so a session can't be taken over by its id alone.
+pub struct Session<In, Out> {
+    core:    Arc<Core>,
+    inbox:   Mutex<Receiver<Vec<u8>>>,
+    /// Messages arrive in sequence whatever connection carried them.
+    replays: Arc<ReplayWindows>,
+    cancel:  CancellationToken,
+    _p:      PhantomData<Mutex<(In, Out)>>,
+}
+
+impl<In: Decode, Out: Encode> Session<In, Out> {
+    /// Connects to a [`SessionServer`]. Reconnects in background if the
+    /// connection drops.
+    pub async fn connect(addr: impl ToString) -> Result<Self> {
+        let addr = addr.to_string();
+        let (core, inbox) = Core::new(System::generate_app_instance_id(), String::new());
+        let cancel = CancellationToken::new();
+
+        let connection = handshake(&core, &addr).await?;
+
+        let cr = core.clone();
+        let cn = cancel.clone();
+
+        spawn(async move {
+            let mut connection = Some(connection);
+
+            loop {
+                if let Some((connection, peer_received)) = connection.take() {
+                    select! {
+                        () = cn.cancelled() => break,
+                        () = cr.run(connection, peer_received) => (),
+                    }
+                }
+
+                select! {
+                    () = cn.cancelled() => break,
+                    () = sleep(RECONNECT_DELAY) => (),
+                }
+
+                match handshake(&cr, &addr).await {
+                    Ok(new) => connection = Some(new),
+                    Err(err) => debug!("Session {} failed to reconnect: {err}", cr.id),
+                }
+            }
+
+            debug!("Session {} closed", cr.id);
+        });
+
+        Ok(Self::new(core, inbox, cancel))
+    }
+
+    fn new(core: Arc<Core>, inbox: Receiver<Vec<u8>>, cancel: CancellationToken) -> Self {
+        Self {
+            core,
+            inbox: Mutex::new(inbox),
+            replays: Arc::new(ReplayWindows::new(MAX_STREAM_SENDERS)),
+            cancel,
+            _p: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.core.id
+    }
+
+    /// Returns once the message is queued. It is delivered when the peer
+    /// is reachable. Waits while [`MAX_UNACKED`] messages are queued.
+    pub async fn send(&self, val: impl Into<Out>) -> Result<()> {
+        self.core.send(val.into().encode()?).await
+    }
+
+    pub async fn receive(&self) -> Result<In> {
+        let data = self
+            .inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(anyhow!("Receiving from closed session"))?;
+        self.replays
+            .check(|| In::decode(data))
+            .map_err(|err| anyhow!("Failed to deserialize from session: {err}"))
+    }
+
+    /// Messages sent but not acknowledged by the peer yet.
+    pub fn pending(&self) -> usize {
+        self.core.state.lock().unacked.len()
+    }
+}
+
+/// Returns the connection and the last sequence number the peer received.
+async fn handshake(core: &Core, addr: &str) -> Result<(Connection, u64)> {
+    let connection = Arc::new(Client::<Bytes, Bytes>::connect(addr).await?);
+
+    let hello = Packet::Hello {
+        session:  core.id.clone(),
+        received: core.received(),
+        token:    core.token.lock().clone(),
+    };
+    connection.send(hello.encode()).await?;
+
+    let Packet::Welcome { received, token } = Packet::decode(connection.receive().await?)? else {
+        bail!("Expected session welcome");
+    };
+
+    *core.token.lock() = token;
+
+    Ok((connection, received))
+}
+
+impl<In, Out> Drop for Session<In, Out> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<In, Out> std::fmt::Debug for Session<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = type_name::<In>();
+        let o = type_name::<Out>();
+
+        f.debug_struct(&format!("Session<{i}, {o}>"))
+            .field("id", &self.core.id)
+            .finish()
+    }
+}
+
+/// Accepts [`Session`] connections and matches reconnects to existing
+/// sessions by id.
+pub struct SessionServer<In, Out> {
+    sessions: Mutex<Receiver<Session<In, Out>>>,
+    cancel:   CancellationToken,
+}
+
+impl<In: Decode, Out: Encode> SessionServer<In, Out> {
+    pub async fn start(port: u16) -> Result<Self> {
+        let server = Server::<Bytes, Bytes>::start(port).await?;
+        let cancel = CancellationToken::new();
+        let (s, r) = channel(1);
+
+        let cn = cancel.clone();
+
+        spawn(async move {
+            let known = Arc::new(SyncMutex::new(HashMap::<String, Weak<Core>>::new()));
+
+            loop {
+                let connection = select! {
+                    () = cn.cancelled() => break,
+                    connection = server.wait_for_new_connection() => Arc::new(connection),
+                };
+
+                let known = known.clone();
+                let s = s.clone();
+                let cn = cn.clone();
+
+                spawn(async move {
+                    if let Err(err) = Self::accept(connection, &known, &s, &cn).await {
+                        error!("Failed to accept session: {err}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            sessions: Mutex::new(r),
+            cancel,
+        })
+    }
+
+    /// Returns only new sessions. Reconnects are attached to existing ones.
+    pub async fn wait_for_new_session(&self) -> Session<In, Out> {
+        self.sessions.lock().await.recv().await.expect("Dropped session server")
+    }
+
+    async fn accept(
+        connection: Connection,
+        known: &SyncMutex<HashMap<String, Weak<Core>>>,
+        sessions: &Sender<Session<In, Out>>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let Packet::Hello {
+            session,
+            received,
+            token,
+        } = Packet::decode(connection.receive().await?)?
+        else {
+            bail!("Expected session hello");
+        };
+
+        let existing = known.lock().get(&session).and_then(Weak::upgrade);
+
+        let core = if let Some(core) = existing {
+            if token.is_empty() || *core.token.lock() != token {
+                bail!("Session {session} resume rejected: invalid token");
+            }
+            debug!("Session {session} resumed");
+            core
+        } else {
+            let (core, inbox) = Core::new(session.clone(), new_token());
+            known.lock().insert(session, Arc::downgrade(&core));
+            known.lock().retain(|_, core| core.strong_count() > 0);
+            sessions
+                .send(Session::new(core.clone(), inbox, cancel.child_token()))
+                .await
+                .map_err(|_| anyhow!("Session server dropped"))?;
+            core
+        };
+
+        let welcome = Packet::Welcome {
+            received: core.received(),
+            token:    core.token.lock().clone(),
+        };
+        connection.send(welcome.encode()).await?;
+
+        // Only a weak reference is kept here, so the session ends when the
+        // application drops its handle.
+        let weak = Arc::downgrade(&core);
+        drop(core);
+
+        select! {
+            () = cancel.cancelled() => (),
+            () = async {
+                if let Some(core) = weak.upgrade() {
+                    core.run(connection, received).await;
+                }
+            } => (),
+        }
+
+        Ok(())
+    }
+}
+
+fn new_token() -> String {
+    let mut token = [0; 32];
+    OsRng.fill_bytes(&mut token);
+    BASE64_STANDARD.encode(token)
+}
+
+impl<In, Out> Drop for SessionServer<In, Out> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+    use tokio::time::{sleep, timeout};
+
+    use crate::{
+        Bytes, Client, MAX_UNACKED, Session, SessionServer, connection::session::Packet, tests::relay,
+    };
+
+    #[test(tokio::test)]
+    async fn test_session() -> Result<()> {
+        let server = SessionServer::<i32, String>::start(57793).await?;
+        let client = Session::<String, i32>::connect("127.0.0.1:57793").await?;
+        let session = server.wait_for_new_session().await;
+
+        assert_eq!(client.id(), session.id());
+
+        for i in 0..10 {
+            client.send(i).await?;
+        }
+
+        for i in 0..10 {
+            assert_eq!(i, session.receive().await?);
+        }
+
+        session.send("hello").await?;
+        assert_eq!("hello", client.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_resume() -> Result<()> {
+        let server = SessionServer::<i32, i32>::start(57794).await?;
+
+        let relay_task = relay(57795, 57794).await?;
+
+        let client = Session::<i32, i32>::connect("127.0.0.1:57795").await?;
+        let session = server.wait_for_new_session().await;
+
+        client.send(1).await?;
+        assert_eq!(1, session.receive().await?);
+
+        relay_task.abort();
+        _ = relay_task.await;
+
+        sleep(Duration::from_millis(100)).await;
+
+        for i in 2..=5 {
+            client.send(i).await?;
+            session.send(i * 10).await?;
+        }
+
+        assert!(client.pending() > 0);
+
+        let _relay = relay(57795, 57794).await?;
+
+        for i in 2..=5 {
+            assert_eq!(i, session.receive().await?);
+            assert_eq!(i * 10, client.receive().await?);
+        }
+
+        client.send(6).await?;
+        assert_eq!(6, session.receive().await?);
+
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, client.pending());
+        assert_eq!(0, session.pending());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_resume_token() -> Result<()> {
+        let server = SessionServer::<i32, i32>::start(57834).await?;
+        let client = Session::<i32, i32>::connect("127.0.0.1:57834").await?;
+        let session = server.wait_for_new_session().await;
+
+        let forged = Client::<Bytes, Bytes>::connect("127.0.0.1:57834").await?;
+        let hello = Packet::Hello {
+            session:  client.id().to_string(),
+            received: 0,
+            token:    "forged".to_string(),
+        };
+        forged.send(hello.encode()).await?;
+
+        assert!(timeout(Duration::from_secs(5), forged.receive()).await?.is_err());
+
+        client.send(1).await?;
+        assert_eq!(1, session.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_queue_limit() -> Result<()> {
+        let server = SessionServer::<i32, i32>::start(57835).await?;
+
+        let relay_task = relay(57836, 57835).await?;
+
+        let client = Session::<i32, i32>::connect("127.0.0.1:57836").await?;
+        let session = server.wait_for_new_session().await;
+
+        relay_task.abort();
+        _ = relay_task.await;
+
+        sleep(Duration::from_millis(100)).await;
+
+        for i in 0..i32::try_from(MAX_UNACKED)? {
+            client.send(i).await?;
+        }
+
+        assert!(timeout(Duration::from_millis(200), client.send(-1)).await.is_err());
+        assert_eq!(MAX_UNACKED, client.pending());
+
+        let _relay = relay(57836, 57835).await?;
+
+        for i in 0..i32::try_from(MAX_UNACKED)? {
+            assert_eq!(i, session.receive().await?);
+        }
+
+        client.send(-1).await?;
+        assert_eq!(-1, session.receive().await?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_cancelled_send() -> Result<()> {
+        let server = SessionServer::<i32, i32>::start(57846).await?;
+        let client = Session::<i32, i32>::connect("127.0.0.1:57846").await?;
+        let session = server.wait_for_new_session().await;
+
+        let writer = client.core.writer.lock().await;
+
+        for i in 0..5 {
+            assert!(timeout(Duration::from_millis(10), client.send(i)).await.is_err());
+        }
+
+        drop(writer);
+
+        assert_eq!(MAX_UNACKED, client.core.capacity.available_permits());
+        assert_eq!(0, client.pending());
+
+        client.send(1).await?;
+        assert_eq!(1, session.receive().await?);
+
+        Ok(())
+    }
+}
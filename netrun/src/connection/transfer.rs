@@ -0,0 +1,374 @@
+use std::{hash::Hasher, io::SeekFrom};
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, copy, sink};
+use twox_hash::XxHash64;
+
+use crate::connection::{Bytes, Channel, Client, Decode, Encode};
+
+const CHUNK: u8 = 0;
+const END: u8 = 1;
+const RESUME: u8 = 2;
+const VERIFIED: u8 = 3;
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// Well below the frame size limit.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobProgress {
+    pub transferred: u64,
+    pub total:       Option<u64>,
+}
+
+/// Chunked transfer of a large blob over a dedicated [`Channel`].
+///
+/// One side calls [`Blob::send`] with a reader, the other [`Blob::receive`]
+/// with a writer. After a reconnect the receiver calls [`Blob::resume`]
+/// with the amount of bytes it has stored, and the sender reads past them.
+/// The checksum covers the bytes transferred in the current session, the
+/// stored prefix is compared by hash before resuming, see
+/// [`Blob::verify_prefix`].
+pub struct Blob {
+    name:          String,
+    chunk_size:    usize,
+    total:         Option<u64>,
+    verify_prefix: bool,
+    progress:      Option<Box<dyn FnMut(BlobProgress) + Send>>,
+}
+
+impl Blob {
+    pub fn new(name: impl ToString) -> Self {
+        Self {
+            name:          name.to_string(),
+            chunk_size:    DEFAULT_CHUNK_SIZE,
+            total:         None,
+            verify_prefix: true,
+            progress:      None,
+        }
+    }
+
+    /// Clamped to 1 byte - 16 MB.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        self
+    }
+
+    /// Whether a resumed transfer hashes the prefix on both sides to detect
+    /// corruption from an earlier attempt. On by default. Without it the
+    /// receiver doesn't read its prefix. Checked only if both sides have it
+    /// on.
+    pub fn verify_prefix(mut self, verify: bool) -> Self {
+        self.verify_prefix = verify;
+        self
+    }
+
+    /// Total size of the blob. Only used for progress reporting.
+    pub fn total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    pub fn on_progress(mut self, action: impl FnMut(BlobProgress) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(action));
+        self
+    }
+
+    /// Streams the reader to the peer. Returns the end offset of the blob.
+    ///
+    /// The reader starts at the beginning of the blob. When the peer resumes,
+    /// the bytes it already has are read and skipped.
+    pub async fn send<In: Decode, Out: Encode>(
+        mut self,
+        client: &Client<In, Out>,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<u64> {
+        let channel: Channel<Bytes, Bytes> = client.open_channel(self.channel_name()).await?;
+
+        let resume = channel.receive().await?;
+        let offset = match resume.first() {
+            Some(&RESUME) => read_u64(&resume, 0)?,
+            _ => bail!("Expected blob resume offset"),
+        };
+
+        let mut buffer = vec![0u8; self.chunk_size];
+
+        // The receiver only sends the hash when it verifies the prefix.
+        if self.verify_prefix
+            && offset > 0
+            && let Ok(expected) = read_u64(&resume, 1)
+        {
+            let ok = hash_prefix(&mut reader, offset, &mut buffer).await? == expected;
+            channel.send(packet(VERIFIED, &[u64::from(ok)])).await?;
+            if !ok {
+                bail!("Blob {} prefix differs on receiver", self.name);
+            }
+        } else {
+            let skipped = copy(&mut (&mut reader).take(offset), &mut sink()).await?;
+            if skipped < offset {
+                bail!("Blob source is shorter than resume offset: {skipped} < {offset}");
+            }
+        }
+
+        let mut hasher = XxHash64::with_seed(0);
+        let mut transferred = offset;
+
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.write(&buffer[..read]);
+
+            let mut chunk = Vec::with_capacity(read + 1);
+            chunk.push(CHUNK);
+            chunk.extend_from_slice(&buffer[..read]);
+            channel.send(Bytes(chunk)).await?;
+
+            transferred += read as u64;
+            self.report(transferred);
+        }
+
+        channel.send(packet(END, &[transferred - offset, hasher.finish()])).await?;
+
+        let verified = channel.receive().await?;
+        match verified.first() {
+            Some(&VERIFIED) if read_u64(&verified, 0)? == 1 => Ok(transferred),
+            Some(&VERIFIED) => bail!("Blob {} checksum mismatch on receiver", self.name),
+            _ => bail!("Expected blob verification"),
+        }
+    }
+
+    /// Writes the whole incoming blob. Returns the end offset of the blob.
+    pub async fn receive<In: Decode, Out: Encode>(
+        self,
+        client: &Client<In, Out>,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<u64> {
+        let channel: Channel<Bytes, Bytes> = client.accept_channel(self.channel_name()).await?;
+        channel.send(packet(RESUME, &[0])).await?;
+
+        self.write_chunks(&channel, &mut writer, 0).await
+    }
+
+    /// Writes the incoming blob after `offset` bytes the writer already has.
+    /// The writer is read to verify the prefix and then seeks to `offset`.
+    /// Returns the end offset of the blob.
+    pub async fn resume<In: Decode, Out: Encode>(
+        self,
+        client: &Client<In, Out>,
+        mut writer: impl AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+        offset: u64,
+    ) -> Result<u64> {
+        let channel: Channel<Bytes, Bytes> = client.accept_channel(self.channel_name()).await?;
+
+        if self.verify_prefix && offset > 0 {
+            writer.seek(SeekFrom::Start(0)).await?;
+            let mut buffer = vec![0u8; self.chunk_size];
+            let hash = hash_prefix(&mut writer, offset, &mut buffer).await?;
+            channel.send(packet(RESUME, &[offset, hash])).await?;
+        } else {
+            channel.send(packet(RESUME, &[offset])).await?;
+        }
+
+        writer.seek(SeekFrom::Start(offset)).await?;
+
+        self.write_chunks(&channel, &mut writer, offset).await
+    }
+
+    async fn write_chunks(
+        mut self,
+        channel: &Channel<Bytes, Bytes>,
+        writer: &mut (impl AsyncWrite + Unpin),
+        offset: u64,
+    ) -> Result<u64> {
+        let mut hasher = XxHash64::with_seed(0);
+        let mut transferred = offset;
+
+        loop {
+            let data = channel.receive().await?;
+
+            match data.first() {
+                Some(&VERIFIED) if read_u64(&data, 0)? == 1 => (),
+                Some(&VERIFIED) => bail!("Blob {} prefix differs on sender", self.name),
+                Some(&CHUNK) => {
+                    writer.write_all(&data[1..]).await?;
+                    hasher.write(&data[1..]);
+
+                    transferred += (data.len() - 1) as u64;
+                    self.report(transferred);
+                }
+                Some(&END) => {
+                    writer.flush().await?;
+
+                    let length = read_u64(&data, 0)?;
+                    let checksum = read_u64(&data, 1)?;
+                    let ok = length == transferred - offset && checksum == hasher.finish();
+
+                    channel.send(packet(VERIFIED, &[u64::from(ok)])).await?;
+
+                    if !ok {
+                        bail!("Blob {} checksum mismatch", self.name);
+                    }
+
+                    return Ok(transferred);
+                }
+                _ => bail!("Unexpected blob packet"),
+            }
+        }
+    }
+
+    fn channel_name(&self) -> String {
+        format!("netrun.blob.{}", self.name)
+    }
+
+    fn report(&mut self, transferred: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress(BlobProgress {
+                transferred,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// Hashes the next `len` bytes.
+async fn hash_prefix(reader: &mut (impl AsyncRead + Unpin), len: u64, buffer: &mut [u8]) -> Result<u64> {
+    let mut hasher = XxHash64::with_seed(0);
+    let mut reader = reader.take(len);
+    let mut total = 0;
+
+    loop {
+        let read = reader.read(buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buffer[..read]);
+        total += read as u64;
+    }
+
+    if total != len {
+        bail!("Blob prefix is shorter than resume offset: {total} < {len}");
+    }
+
+    Ok(hasher.finish())
+}
+
+fn packet(tag: u8, values: &[u64]) -> Bytes {
+    let mut data = Vec::with_capacity(1 + values.len() * 8);
+    data.push(tag);
+    for value in values {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    Bytes(data)
+}
+
+fn read_u64(data: &[u8], index: usize) -> Result<u64> {
+    let start = 1 + index * 8;
+    let Some(bytes) = data.get(start..start + 8) else {
+        bail!("Blob packet is too short: {}", data.len());
+    };
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Cursor,
+        net::Ipv4Addr,
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+    };
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use crate::{Blob, Client, Server};
+
+    fn blob() -> Vec<u8> {
+        (0..3 * 1024 * 1024 + 17).map(|i| (i % 241) as u8).collect()
+    }
+
+    #[test(tokio::test)]
+    async fn test_blob_transfer() -> Result<()> {
+        let server = Server::<(), ()>::start(57783).await?;
+        let client = Client::<(), ()>::connect((Ipv4Addr::LOCALHOST, 57783)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let data = blob();
+        let progress = Arc::new(AtomicU64::new(0));
+        let pr = progress.clone();
+
+        let (sent, received) = tokio::join!(Blob::new("file").send(&client, data.as_slice()), async {
+            let mut out = vec![];
+            let size = Blob::new("file")
+                .total(data.len() as u64)
+                .on_progress(move |p| pr.store(p.transferred, Ordering::Relaxed))
+                .receive(&connection, &mut out)
+                .await?;
+            anyhow::Ok((size, out))
+        });
+
+        let (size, out) = received?;
+
+        assert_eq!(sent?, data.len() as u64);
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(progress.load(Ordering::Relaxed), data.len() as u64);
+        assert!(out == data);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn test_blob_resume() -> Result<()> {
+        let server = Server::<(), ()>::start(57784).await?;
+        let client = Client::<(), ()>::connect((Ipv4Addr::LOCALHOST, 57784)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let data = blob();
+        let offset = 1024 * 1024 + 5;
+
+        let mut out = Cursor::new(data[..offset].to_vec());
+
+        let (sent, received) = tokio::join!(
+            Blob::new("resumed").chunk_size(1000).send(&connection, data.as_slice()),
+            Blob::new("resumed").resume(&client, &mut out, offset as u64),
+        );
+
+        assert_eq!(sent?, data.len() as u64);
+        assert_eq!(received?, data.len() as u64);
+        assert!(out.into_inner() == data);
+
+        // Without verification the sender only skips the prefix.
+        let mut out = Cursor::new(data[..offset].to_vec());
+
+        let (sent, received) = tokio::join!(
+            Blob::new("unverified").verify_prefix(false).send(&connection, data.as_slice()),
+            Blob::new("unverified").resume(&client, &mut out, offset as u64),
+        );
+
+        assert_eq!(sent?, data.len() as u64);
+        assert_eq!(received?, data.len() as u64);
+        assert!(out.into_inner() == data);
+
+        // Corrupted by an earlier attempt.
+        let mut corrupted = data[..offset].to_vec();
+        corrupted[10] ^= 1;
+        let mut out = Cursor::new(corrupted);
+
+        let (sent, received) = tokio::join!(
+            Blob::new("corrupted").chunk_size(0).send(&connection, Cursor::new(&data)),
+            Blob::new("corrupted").resume(&client, &mut out, offset as u64),
+        );
+
+        assert!(sent.is_err());
+        assert!(received.is_err());
+        assert_eq!(offset, out.into_inner().len());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 #[cfg(not_wasm)]
+pub mod blocking;
+#[cfg(not_wasm)]
 mod connection;
 mod function;
 pub mod rest;
@@ -4,9 +4,12 @@ use anyhow::{Result, anyhow};
 use log::{debug, error};
 use reqwest::Client;
 use serde::{Serialize, de::DeserializeOwned};
-use serde_json::{from_str, to_string};
+use serde_json::to_string;
 
-use crate::rest::{Method, Response, RestAPI};
+use crate::{
+    rest::{Method, Response, RestAPI},
+    serde::decode_json,
+};
 
 #[derive(Debug)]
 pub struct Request<In: Serialize, Out: DeserializeOwned> {
@@ -67,6 +70,21 @@ impl<In: Serialize, Out: DeserializeOwned> Request<In, Out> {
         .await
     }
 
+    /// Blocking version of [`Request::send`]. See [`crate::blocking`].
+    #[cfg(not_wasm)]
+    pub fn send_blocking(&self, param: impl Borrow<In>) -> Result<Out>
+    where Out: Send {
+        let body = to_body(param)?;
+
+        crate::blocking::block_on(request_object(
+            self.api.client(),
+            self.method,
+            self.full_url(),
+            self.api.headers(),
+            body,
+        ))
+    }
+
     pub async fn with_token(&self, param: impl Borrow<In>, token: impl ToString) -> Result<Out> {
         self.with_headers(param, [("token".to_string(), token.to_string())]).await
     }
@@ -106,19 +124,11 @@ where
     } else if response.status != 200 {
         Err(anyhow!("[{}] {}", response.status, response.body))
     } else {
-        Ok(parse(&response.body)?)
-    }
-}
-
-fn parse<T: DeserializeOwned>(json: impl ToString) -> Result<T> {
-    let json = json.to_string();
-    match from_str(&json) {
-        Ok(obj) => Ok(obj),
-        Err(error) => {
-            let message = format!("Failed to parse {} from {json}. Error: {error}", type_name::<T>());
-            error!("{message}");
-            Err(anyhow!(message))
-        }
+        let body = response.body.as_bytes();
+        decode_json(body, body.len()).map_err(|err| {
+            error!("{err}");
+            err.into()
+        })
     }
 }
 
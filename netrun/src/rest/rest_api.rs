@@ -51,6 +51,17 @@ impl RestAPI {
     pub fn set_access_token(&self, token: impl ToString) {
         self.add_header("token", token);
     }
+
+    /// Sends all requests of this API through the proxy. Has to be called
+    /// before the first request.
+    #[cfg(not_wasm)]
+    pub fn set_proxy(&self, proxy: &crate::Proxy) -> anyhow::Result<()> {
+        let client = Client::builder().proxy(proxy.to_reqwest()?).build()?;
+
+        self.client
+            .set(client)
+            .map_err(|_| anyhow::anyhow!("Proxy for {} is set after the first request", self.base_url))
+    }
 }
 
 impl RestAPI {
@@ -0,0 +1,506 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow, bail};
+use lz4_flex::{block, compress_prepend_size};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+#[cfg(not_wasm)]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::serde::scope::Scoped;
+
+/// Default limit of [`decompress`], so a peer can't make us allocate whatever
+/// size it puts into the prefix.
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+/// Smaller messages are sent uncompressed by default. Compression rarely
+/// pays off for them and adds a few bytes of its own.
+pub const COMPRESSION_THRESHOLD: usize = 128;
+
+/// Default zstd level, a good balance of speed and ratio.
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// First bytes of a zstd dictionary, followed by its id.
+const DICTIONARY_MAGIC: [u8; 4] = 0xEC30_A437u32.to_le_bytes();
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    /// Fast, the default.
+    #[default]
+    Lz4,
+    /// LZ4 frame format, compressed while serializing and decompressed while
+    /// parsing, so large messages are never fully in memory uncompressed.
+    /// Always applied, regardless of the threshold.
+    Lz4Frame,
+    /// Better ratio, supports levels and dictionaries. Not available on wasm.
+    Zstd,
+}
+
+/// Why [`decompress`] failed. Peers can send anything, so none of these
+/// panic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecompressError {
+    /// Shorter than the size prefix.
+    TooShort(usize),
+    /// Declared size is over the limit.
+    TooLarge {
+        size:  usize,
+        limit: usize,
+    },
+    /// Compressed with a [`Dictionary`] which wasn't registered here.
+    UnknownDictionary(u32),
+    Corrupted(String),
+}
+
+impl Display for DecompressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "Compressed data is too short: {len} bytes"),
+            Self::TooLarge { size, limit } => {
+                write!(f, "Decompressed size {size} exceeds limit of {limit} bytes")
+            }
+            Self::UnknownDictionary(id) => write!(f, "Unknown compression dictionary: {id}"),
+            Self::Corrupted(err) => write!(f, "Corrupted compressed data: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+pub fn compress(buf: &[u8]) -> Vec<u8> {
+    compress_prepend_size(buf)
+}
+
+pub fn decompress(buf: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_with_limit(buf, MAX_DECOMPRESSED_SIZE)
+}
+
+/// Reverses [`compress`]. Fails before allocating if the size prefix is over
+/// `limit`.
+pub fn decompress_with_limit(buf: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+    let (size, data) = block::uncompressed_size(buf).map_err(|_| DecompressError::TooShort(buf.len()))?;
+
+    if size > limit {
+        return Err(DecompressError::TooLarge { size, limit });
+    }
+
+    let decompressed =
+        block::decompress(data, size).map_err(|err| DecompressError::Corrupted(err.to_string()))?;
+
+    if decompressed.len() != size {
+        return Err(DecompressError::Corrupted(format!(
+            "expected {size} bytes, got {}",
+            decompressed.len()
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(not_wasm)]
+pub fn compress_zstd(buf: &[u8], level: i32, dictionary: Option<&Dictionary>) -> Result<Vec<u8>> {
+    let Some(dictionary) = dictionary else {
+        return Ok(zstd::bulk::Compressor::new(level)?.compress(buf)?);
+    };
+
+    let prepared = dictionary.encoder(level);
+    Ok(zstd::bulk::Compressor::with_prepared_dictionary(&prepared)?.compress(buf)?)
+}
+
+#[cfg(wasm)]
+pub fn compress_zstd(_: &[u8], _: i32, _: Option<&Dictionary>) -> Result<Vec<u8>> {
+    bail!("Zstd is not available on wasm")
+}
+
+/// Reverses [`compress_zstd`]. The dictionary is looked up by the id stored
+/// in the data.
+#[cfg(not_wasm)]
+pub fn decompress_zstd(buf: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+    use zstd::zstd_safe::{get_dict_id_from_frame, get_frame_content_size};
+
+    let size = get_frame_content_size(buf)
+        .map_err(|_| DecompressError::Corrupted("invalid zstd frame".to_string()))?
+        .ok_or_else(|| DecompressError::Corrupted("unknown decompressed size".to_string()))?;
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+
+    if size > limit {
+        return Err(DecompressError::TooLarge { size, limit });
+    }
+
+    let decompressed = match get_dict_id_from_frame(buf) {
+        Some(id) => {
+            let dictionary =
+                Dictionary::registered(id.get()).ok_or(DecompressError::UnknownDictionary(id.get()))?;
+            zstd::bulk::Decompressor::with_prepared_dictionary(dictionary.decoder())
+                .and_then(|mut decompressor| decompressor.decompress(buf, size))
+        }
+        None => zstd::bulk::decompress(buf, size),
+    }
+    .map_err(|err| DecompressError::Corrupted(err.to_string()))?;
+
+    if decompressed.len() != size {
+        return Err(DecompressError::Corrupted(format!(
+            "expected {size} bytes, got {}",
+            decompressed.len()
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(wasm)]
+pub fn decompress_zstd(_: &[u8], _: usize) -> Result<Vec<u8>, DecompressError> {
+    Err(DecompressError::Corrupted(
+        "zstd is not available on wasm".to_string(),
+    ))
+}
+
+static DICTIONARIES: LazyLock<RwLock<HashMap<u32, Dictionary>>> = LazyLock::new(RwLock::default);
+
+/// Zstd dictionary trained from sample messages. Shrinks small repetitive
+/// messages, like JSON of the same struct, far better than plain
+/// compression.
+///
+/// Compressed data names its dictionary by id, so the receiving process has
+/// to [`register`](Dictionary::register) the same one.
+#[derive(Clone)]
+pub struct Dictionary {
+    id:       u32,
+    data:     Arc<[u8]>,
+    #[cfg(not_wasm)]
+    prepared: Arc<Prepared>,
+}
+
+/// Digested forms of a [`Dictionary`], built on first use. Building them
+/// costs far more than compressing a small message.
+#[cfg(not_wasm)]
+#[derive(Default)]
+struct Prepared {
+    /// By zstd level, which is baked into the digested dictionary.
+    encoders: RwLock<HashMap<i32, Arc<EncoderDictionary<'static>>>>,
+    decoder:  std::sync::OnceLock<DecoderDictionary<'static>>,
+}
+
+impl Dictionary {
+    /// Needs a few dozen samples at least. `max_size` of a few KB is enough
+    /// for most message types.
+    #[cfg(not_wasm)]
+    pub fn train(samples: &[impl AsRef<[u8]>], max_size: usize) -> Result<Self> {
+        Self::from_bytes(zstd::dict::from_samples(samples, max_size)?)
+    }
+
+    /// Loads a dictionary saved with [`Dictionary::as_bytes`].
+    pub fn from_bytes(data: impl Into<Arc<[u8]>>) -> Result<Self> {
+        let data = data.into();
+
+        let Some((magic, rest)) = data.split_first_chunk::<4>() else {
+            bail!("Dictionary is too short: {} bytes", data.len());
+        };
+
+        if *magic != DICTIONARY_MAGIC {
+            bail!("Not a zstd dictionary");
+        }
+
+        let id = rest
+            .first_chunk::<4>()
+            .map(|id| u32::from_le_bytes(*id))
+            .filter(|id| *id != 0)
+            .ok_or_else(|| anyhow!("Dictionary has no id"))?;
+
+        Ok(Self {
+            id,
+            data,
+            #[cfg(not_wasm)]
+            prepared: Arc::default(),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Makes it available for decompressing messages which use it.
+    pub fn register(&self) {
+        DICTIONARIES.write().insert(self.id, self.clone());
+    }
+
+    pub fn registered(id: u32) -> Option<Self> {
+        DICTIONARIES.read().get(&id).cloned()
+    }
+
+    #[cfg(not_wasm)]
+    fn encoder(&self, level: i32) -> Arc<EncoderDictionary<'static>> {
+        if let Some(encoder) = self.prepared.encoders.read().get(&level) {
+            return encoder.clone();
+        }
+
+        self.prepared
+            .encoders
+            .write()
+            .entry(level)
+            .or_insert_with(|| Arc::new(EncoderDictionary::copy(&self.data, level)))
+            .clone()
+    }
+
+    #[cfg(not_wasm)]
+    fn decoder(&self) -> &DecoderDictionary<'static> {
+        self.prepared.decoder.get_or_init(|| DecoderDictionary::copy(&self.data))
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl std::fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+static TOTAL: CompressionCounters = CompressionCounters::new();
+
+thread_local! {
+    /// Counters of the connection encoding on this thread, see
+    /// [`CompressionCounters::count`].
+    static CURRENT: RefCell<Option<Arc<CompressionCounters>>> = const { RefCell::new(None) };
+}
+
+/// Totals of messages encoded with a compressing
+/// [`Encoding`](crate::serde::Encoding), either by the whole process or by
+/// one connection. Streamed [`Compression::Lz4Frame`] messages aren't
+/// counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub compressed:   u64,
+    /// Sent uncompressed, because they were below the threshold or didn't
+    /// shrink.
+    pub skipped:      u64,
+    /// Size of compressed messages before compression.
+    pub input_bytes:  u64,
+    pub output_bytes: u64,
+}
+
+impl CompressionStats {
+    /// All messages encoded by this process. Each connection has its own
+    /// figure too, see
+    /// [`Client::compression_stats`](crate::Client::compression_stats).
+    pub fn get() -> Self {
+        TOTAL.get()
+    }
+
+    /// Compressed size to original size. Lower is better.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 1.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+
+    pub(crate) fn record(input: usize, output: usize) {
+        TOTAL.add(input, output);
+        CURRENT.with_borrow(|current| {
+            if let Some(counters) = current {
+                counters.add(input, output);
+            }
+        });
+    }
+
+    pub(crate) fn record_skipped() {
+        TOTAL.skip();
+        CURRENT.with_borrow(|current| {
+            if let Some(counters) = current {
+                counters.skip();
+            }
+        });
+    }
+}
+
+/// Live [`CompressionStats`] of one connection.
+#[derive(Debug, Default)]
+pub(crate) struct CompressionCounters {
+    compressed:   AtomicU64,
+    skipped:      AtomicU64,
+    input_bytes:  AtomicU64,
+    output_bytes: AtomicU64,
+}
+
+impl CompressionCounters {
+    const fn new() -> Self {
+        Self {
+            compressed:   AtomicU64::new(0),
+            skipped:      AtomicU64::new(0),
+            input_bytes:  AtomicU64::new(0),
+            output_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> CompressionStats {
+        CompressionStats {
+            compressed:   self.compressed.load(Ordering::Relaxed),
+            skipped:      self.skipped.load(Ordering::Relaxed),
+            input_bytes:  self.input_bytes.load(Ordering::Relaxed),
+            output_bytes: self.output_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Messages encoded by `encode` are counted here as well as in the
+    /// process totals.
+    pub fn count<R>(self: &Arc<Self>, encode: impl FnOnce() -> R) -> R {
+        let _scoped = Scoped::set(&CURRENT, self.clone());
+        encode()
+    }
+
+    fn add(&self, input: usize, output: usize) {
+        self.compressed.fetch_add(1, Ordering::Relaxed);
+        self.input_bytes.fetch_add(input as u64, Ordering::Relaxed);
+        self.output_bytes.fetch_add(output as u64, Ordering::Relaxed);
+    }
+
+    fn skip(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    use crate::serde::{
+        Codec, DecompressError, Dictionary, Encoding, FLAG_DICTIONARY, Format, Header, MAX_DECOMPRESSED_SIZE,
+        ZSTD_LEVEL, compress, compress_zstd, decompress, decompress_with_limit, decompress_zstd,
+    };
+
+    #[test]
+    fn test_malformed_compressed_data() -> Result<()> {
+        assert_eq!(Err(DecompressError::TooShort(0)), decompress(&[]));
+        assert_eq!(Err(DecompressError::TooShort(3)), decompress(&[1, 2, 3]));
+
+        // Claims 4 GB.
+        assert_eq!(
+            Err(DecompressError::TooLarge {
+                size:  u32::MAX as usize,
+                limit: MAX_DECOMPRESSED_SIZE,
+            }),
+            decompress(&[0xFF, 0xFF, 0xFF, 0xFF, 0])
+        );
+
+        let data = compress(&[7; 1000]);
+        assert_eq!(vec![7; 1000], decompress(&data)?);
+
+        assert_eq!(
+            Err(DecompressError::TooLarge {
+                size:  1000,
+                limit: 999,
+            }),
+            decompress_with_limit(&data, 999)
+        );
+
+        let mut corrupted = data.clone();
+        corrupted.truncate(data.len() - 2);
+        assert!(matches!(
+            decompress(&corrupted),
+            Err(DecompressError::Corrupted(_))
+        ));
+
+        let mut garbage = 100u32.to_le_bytes().to_vec();
+        garbage.extend([0xF0; 20]);
+        assert!(matches!(decompress(&garbage), Err(DecompressError::Corrupted(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_zstd() -> Result<()> {
+        let data = compress_zstd(&[7; 1000], 19, None)?;
+        assert!(data.len() < 30);
+        assert_eq!(vec![7; 1000], decompress_zstd(&data, 1000)?);
+
+        assert_eq!(
+            Err(DecompressError::TooLarge {
+                size:  1000,
+                limit: 999,
+            }),
+            decompress_zstd(&data, 999)
+        );
+
+        assert!(matches!(
+            decompress_zstd(&data[..data.len() - 2], 1000),
+            Err(DecompressError::Corrupted(_))
+        ));
+        assert!(matches!(
+            decompress_zstd(&[1, 2, 3], 1000),
+            Err(DecompressError::Corrupted(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dictionary() -> Result<()> {
+        let samples: Vec<String> = (0..500)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{i},"name":"sensor-{}","kind":"temperature","unit":"celsius","value":{}}}"#,
+                    i % 7,
+                    i * 3
+                )
+            })
+            .collect();
+
+        let dictionary = Dictionary::train(&samples, 4096)?;
+        let message = br#"{"id":1000,"name":"sensor-3","kind":"temperature","unit":"celsius","value":42}"#;
+
+        let plain = compress_zstd(message, 3, None)?;
+        let trained = compress_zstd(message, 3, Some(&dictionary))?;
+        assert!(
+            trained.len() * 2 < plain.len(),
+            "{} {}",
+            trained.len(),
+            plain.len()
+        );
+
+        assert_eq!(
+            Err(DecompressError::UnknownDictionary(dictionary.id())),
+            decompress_zstd(&trained, 1000)
+        );
+
+        dictionary.register();
+        assert_eq!(message.to_vec(), decompress_zstd(&trained, 1000)?);
+
+        // Registered copies share the prepared dictionaries.
+        let registered = Dictionary::registered(dictionary.id()).unwrap();
+        assert!(Arc::ptr_eq(&dictionary.encoder(3), &registered.encoder(3)));
+        assert!(std::ptr::eq(dictionary.decoder(), registered.decoder()));
+
+        let encoding = Encoding::zstd(Format::Json, ZSTD_LEVEL).threshold(0).dictionary(&dictionary);
+        let value: serde_json::Value = serde_json::from_slice(message)?;
+        let data = encoding.encode(&value)?;
+        assert_eq!(FLAG_DICTIONARY, Header::parse(&data)?.unwrap().0.flags);
+        assert_eq!(value, Encoding::default().decode::<serde_json::Value>(&data)?);
+
+        let loaded = Dictionary::from_bytes(dictionary.as_bytes().to_vec())?;
+        assert_eq!(dictionary.id(), loaded.id());
+        assert!(Dictionary::from_bytes(vec![1, 2, 3]).is_err());
+        assert!(Dictionary::from_bytes(vec![0; 100]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+use std::fmt::{Display, Formatter};
+
+#[cfg(not_wasm)]
+pub use keys::*;
+
+/// Why an encrypted message was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptError {
+    /// Sent without encryption to an [`Encoding`](crate::serde::Encoding)
+    /// which requires it.
+    Unencrypted,
+    Truncated(usize),
+    /// Encrypted with a [`Key`] which isn't registered here.
+    UnknownKey(u32),
+    /// Tampered with or encrypted with a different key under the same id.
+    Authentication,
+    /// Counter was already received or is too old to tell.
+    Replayed(u64),
+    Unsupported,
+}
+
+impl Display for DecryptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unencrypted => write!(f, "Unencrypted message rejected"),
+            Self::Truncated(len) => write!(f, "Encrypted message is too short: {len} bytes"),
+            Self::UnknownKey(id) => write!(f, "Unknown encryption key: {id}"),
+            Self::Authentication => write!(f, "Message failed authentication"),
+            Self::Replayed(counter) => write!(f, "Replayed message: counter {counter}"),
+            Self::Unsupported => write!(f, "Encryption is not available on wasm"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Received counters of one sender. Accepts each counter once, in any order
+/// within the last [`ReplayWindow::SIZE`] counters.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ReplayWindow {
+    highest: u64,
+    /// Bit `n` is set when `highest - n` was received.
+    seen:    u64,
+}
+
+impl ReplayWindow {
+    const SIZE: u64 = u64::BITS as u64;
+
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= Self::SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = counter;
+            return true;
+        }
+
+        let age = self.highest - counter;
+
+        if age >= Self::SIZE || self.seen & (1 << age) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+#[cfg(wasm)]
+pub(crate) fn seal(_: u32, _: &[u8], _: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    Err(DecryptError::Unsupported)
+}
+
+#[cfg(wasm)]
+pub(crate) fn open(_: &[u8], _: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    Err(DecryptError::Unsupported)
+}
+
+#[cfg(not_wasm)]
+mod keys {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        sync::{
+            Arc, LazyLock,
+            atomic::{AtomicU64, Ordering},
+        },
+    };
+
+    use anyhow::{Result, anyhow};
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use chacha20poly1305::{
+        KeyInit, XChaCha20Poly1305, XNonce,
+        aead::{Aead, OsRng, Payload, rand_core::RngCore},
+    };
+    use parking_lot::{Mutex, RwLock};
+
+    use crate::{
+        secret::Secret,
+        serde::{DecryptError, crypto::ReplayWindow, scope::Scoped},
+    };
+
+    pub const KEY_LEN: usize = 32;
+
+    const SESSION_LEN: usize = 16;
+    /// `[key id u32][session 16][counter u64]`, the session and the counter
+    /// make the nonce.
+    const PREFIX_LEN: usize = 4 + SESSION_LEN + 8;
+
+    /// Sending side of a registered key. Nonces are the random session
+    /// followed by a counter, so they never repeat, even across processes
+    /// sharing the key.
+    struct Registered {
+        key:     Key,
+        cipher:  XChaCha20Poly1305,
+        session: [u8; SESSION_LEN],
+        counter: AtomicU64,
+    }
+
+    static KEYS: LazyLock<RwLock<HashMap<u32, Arc<Registered>>>> = LazyLock::new(RwLock::default);
+    /// Key id and session of a sender.
+    type Sender = (u32, [u8; SESSION_LEN]);
+
+    /// Used by messages decoded outside of [`ReplayWindows::check`].
+    static WINDOWS: LazyLock<Arc<ReplayWindows>> =
+        LazyLock::new(|| Arc::new(ReplayWindows::new(MAX_SENDERS)));
+
+    thread_local! {
+        static CURRENT: RefCell<Option<Arc<ReplayWindows>>> = const { RefCell::new(None) };
+    }
+
+    /// Senders remembered by the process wide [`ReplayWindows`]. Every
+    /// process registering a key is a sender with its own session.
+    pub(crate) const MAX_SENDERS: usize = 4096;
+
+    /// Senders remembered by the [`ReplayWindows`] of one stream. It
+    /// usually hears one peer, with a session per key.
+    pub(crate) const MAX_STREAM_SENDERS: usize = 16;
+
+    /// Replay windows of the senders heard on one stream, e.g. a lane of a
+    /// channel. Messages are checked in the order they are decoded, so it
+    /// should be the order they arrived in, give or take a few concurrent
+    /// receivers.
+    ///
+    /// Holds at most `limit` senders. When a new one comes in, the one heard
+    /// from least recently is forgotten, and its old messages would be
+    /// accepted again. Only key holders can start sessions, so the limit is
+    /// reached only by a peer that could send anything anyway.
+    pub(crate) struct ReplayWindows {
+        limit:   usize,
+        senders: Mutex<HashMap<Sender, (ReplayWindow, u64)>>,
+        heard:   AtomicU64,
+    }
+
+    impl ReplayWindows {
+        pub fn new(limit: usize) -> Self {
+            Self {
+                limit,
+                senders: Mutex::default(),
+                heard: AtomicU64::default(),
+            }
+        }
+
+        /// Encrypted messages decoded by `decode` are checked against these
+        /// windows instead of the process wide ones.
+        pub fn check<R>(self: &Arc<Self>, decode: impl FnOnce() -> R) -> R {
+            let _scoped = Scoped::set(&CURRENT, self.clone());
+            decode()
+        }
+
+        fn accept(&self, sender: Sender, counter: u64) -> bool {
+            let heard = self.heard.fetch_add(1, Ordering::Relaxed);
+            let mut senders = self.senders.lock();
+
+            if !senders.contains_key(&sender)
+                && senders.len() >= self.limit
+                && let Some(oldest) =
+                    senders.iter().min_by_key(|(_, (_, heard))| *heard).map(|(sender, _)| *sender)
+            {
+                senders.remove(&oldest);
+            }
+
+            let (window, last) = senders.entry(sender).or_default();
+            *last = heard;
+            window.accept(counter)
+        }
+
+        fn forget(&self, id: u32) {
+            self.senders.lock().retain(|(key, _), _| *key != id);
+        }
+
+        #[cfg(test)]
+        pub fn len(&self) -> usize {
+            self.senders.lock().len()
+        }
+    }
+
+    /// Pre-shared XChaCha20-Poly1305 key.
+    ///
+    /// Messages name their key by id, so keys can be rotated: register the
+    /// new key everywhere, switch senders to it, then unregister the old one.
+    #[derive(Clone)]
+    pub struct Key {
+        id:    u32,
+        bytes: [u8; KEY_LEN],
+    }
+
+    impl Key {
+        pub const fn new(id: u32, bytes: [u8; KEY_LEN]) -> Self {
+            Self { id, bytes }
+        }
+
+        pub fn generate(id: u32) -> Self {
+            let mut bytes = [0; KEY_LEN];
+            OsRng.fill_bytes(&mut bytes);
+            Self { id, bytes }
+        }
+
+        pub fn from_base64(id: u32, encoded: &str) -> Result<Self> {
+            let bytes = BASE64_STANDARD.decode(encoded.trim())?;
+            let bytes = bytes
+                .try_into()
+                .map_err(|bytes: Vec<u8>| anyhow!("Key has to be {KEY_LEN} bytes, got {}", bytes.len()))?;
+            Ok(Self { id, bytes })
+        }
+
+        /// Loads a base64 key, as produced by [`Key::to_base64`].
+        pub async fn from_secret(id: u32, secret: &Secret) -> Result<Self> {
+            Self::from_base64(id, &secret.get().await?)
+        }
+
+        pub fn to_base64(&self) -> String {
+            BASE64_STANDARD.encode(self.bytes)
+        }
+
+        pub fn id(&self) -> u32 {
+            self.id
+        }
+
+        /// Makes it available for encrypting and decrypting. Replaces a
+        /// different key with the same id.
+        pub fn register(&self) {
+            let mut keys = KEYS.write();
+
+            if keys.get(&self.id).is_some_and(|registered| registered.key.bytes == self.bytes) {
+                return;
+            }
+
+            let mut session = [0; SESSION_LEN];
+            OsRng.fill_bytes(&mut session);
+
+            keys.insert(
+                self.id,
+                Arc::new(Registered {
+                    key: self.clone(),
+                    cipher: XChaCha20Poly1305::new(&self.bytes.into()),
+                    session,
+                    counter: AtomicU64::new(0),
+                }),
+            );
+        }
+
+        pub fn unregister(id: u32) {
+            KEYS.write().remove(&id);
+            WINDOWS.forget(id);
+        }
+    }
+
+    #[allow(clippy::missing_fields_in_debug)]
+    impl std::fmt::Debug for Key {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Key").field("id", &self.id).finish()
+        }
+    }
+
+    fn registered(id: u32) -> Result<Arc<Registered>, DecryptError> {
+        KEYS.read().get(&id).cloned().ok_or(DecryptError::UnknownKey(id))
+    }
+
+    /// Encrypts `data` with a registered key. `aad` is authenticated but not
+    /// encrypted.
+    pub(crate) fn seal(id: u32, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        let key = registered(id)?;
+        let counter = key.counter.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut nonce = XNonce::default();
+        nonce[..SESSION_LEN].copy_from_slice(&key.session);
+        nonce[SESSION_LEN..].copy_from_slice(&counter.to_le_bytes());
+
+        let encrypted = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| DecryptError::Authentication)?;
+
+        let mut sealed = Vec::with_capacity(PREFIX_LEN + encrypted.len());
+        sealed.extend(id.to_le_bytes());
+        sealed.extend(nonce);
+        sealed.extend(encrypted);
+        Ok(sealed)
+    }
+
+    /// Reverses [`seal`]. Each message is accepted only once by the same
+    /// [`ReplayWindows`].
+    pub(crate) fn open(aad: &[u8], data: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        let Some((prefix, encrypted)) = data.split_at_checked(PREFIX_LEN) else {
+            return Err(DecryptError::Truncated(data.len()));
+        };
+
+        let (id, nonce) = prefix.split_at(4);
+        let id = u32::from_le_bytes(id.try_into().expect("Split at 4"));
+        let nonce = XNonce::from_slice(nonce);
+
+        let session: [u8; SESSION_LEN] = nonce[..SESSION_LEN].try_into().expect("Nonce is 24 bytes");
+        let counter = u64::from_le_bytes(nonce[SESSION_LEN..].try_into().expect("Nonce is 24 bytes"));
+
+        let decrypted = registered(id)?
+            .cipher
+            .decrypt(nonce, Payload { msg: encrypted, aad })
+            .map_err(|_| DecryptError::Authentication)?;
+
+        let windows = CURRENT.with_borrow(Clone::clone).unwrap_or_else(|| WINDOWS.clone());
+
+        // Only authenticated counters may move the window.
+        if !windows.accept((id, session), counter) {
+            return Err(DecryptError::Replayed(counter));
+        }
+
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    use crate::serde::{
+        Codec, DecodeError, DecryptError, Encoding, FLAG_ENCRYPTED, Format, HEADER_LEN, Header, Key,
+        ReplayWindows, crypto::ReplayWindow,
+    };
+
+    fn decrypt_error(err: &anyhow::Error) -> Option<&DecryptError> {
+        err.downcast_ref::<DecodeError>()?.cause()
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(window.accept(2));
+        assert!(!window.accept(2));
+        assert!(!window.accept(3));
+
+        assert!(window.accept(100));
+        assert!(window.accept(40));
+        assert!(!window.accept(36));
+        assert!(!window.accept(40));
+        assert!(window.accept(1000));
+        assert!(!window.accept(100));
+    }
+
+    #[test]
+    fn test_encryption() -> Result<()> {
+        let key = Key::generate(70_001);
+        let encoding = Encoding::default().encrypt(&key);
+
+        let text = "secret text ".repeat(20);
+        let data = encoding.encode(&text)?;
+
+        let header = Header::parse(&data)?.unwrap().0;
+        assert_eq!(FLAG_ENCRYPTED, header.flags & FLAG_ENCRYPTED);
+        assert!(!data.windows(6).any(|window| window == b"secret"));
+
+        // Any receiver with the key registered can read it.
+        assert_eq!(text, Encoding::default().decode::<String>(&data)?);
+
+        let err = encoding.decode::<String>(&data).unwrap_err();
+        assert!(matches!(decrypt_error(&err), Some(DecryptError::Replayed(_))));
+
+        let mut tampered = encoding.encode(&text)?;
+        *tampered.last_mut().unwrap() ^= 1;
+        let err = encoding.decode::<String>(&tampered).unwrap_err();
+        assert_eq!(Some(&DecryptError::Authentication), decrypt_error(&err));
+
+        // The header is authenticated too.
+        let mut tampered = encoding.encode(&text)?;
+        tampered[HEADER_LEN - 2] = 0;
+        let err = encoding.decode::<String>(&tampered).unwrap_err();
+        assert_eq!(Some(&DecryptError::Authentication), decrypt_error(&err));
+
+        let plain = Encoding::default().encode(&text)?;
+        let err = encoding.decode::<String>(&plain).unwrap_err();
+        assert_eq!(Some(&DecryptError::Unencrypted), decrypt_error(&err));
+
+        assert!(Encoding::legacy().encrypt(&key).envelope(false).encode(&text).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_windows() -> Result<()> {
+        let encoding = Encoding::compressed(Format::Json).encrypt(&Key::generate(70_004));
+        let data = encoding.encode(&5)?;
+
+        let stream = Arc::new(ReplayWindows::new(2));
+        let other = Arc::new(ReplayWindows::new(2));
+
+        assert_eq!(5, stream.check(|| encoding.decode::<i32>(&data))?);
+        let err = stream.check(|| encoding.decode::<i32>(&data)).unwrap_err();
+        assert!(matches!(decrypt_error(&err), Some(DecryptError::Replayed(_))));
+
+        // Each stream has its own windows, the process wide ones are separate too.
+        assert_eq!(5, other.check(|| encoding.decode::<i32>(&data))?);
+        assert_eq!(5, encoding.decode::<i32>(&data)?);
+
+        for id in 70_005..70_008 {
+            let sender = Encoding::compressed(Format::Json).encrypt(&Key::generate(id));
+            stream.check(|| sender.decode::<i32>(&sender.encode(&1)?))?;
+        }
+
+        assert_eq!(2, stream.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rotation() -> Result<()> {
+        let old = Key::generate(70_002);
+        let new = Key::from_base64(70_003, &Key::generate(0).to_base64())?;
+
+        let sender = Encoding::raw(Format::Postcard).encrypt(&old);
+        let old_message = sender.encode(&1)?;
+        let stale_message = sender.encode(&3)?;
+
+        let receiver = Encoding::default().encrypt(&new);
+        let new_message = receiver.encode(&2)?;
+
+        assert_eq!(1, receiver.decode::<i32>(&old_message)?);
+        assert_eq!(2, receiver.decode::<i32>(&new_message)?);
+
+        Key::unregister(old.id());
+
+        let err = receiver.decode::<i32>(&stale_message).unwrap_err();
+        assert_eq!(Some(&DecryptError::UnknownKey(70_002)), decrypt_error(&err));
+
+        assert!(Key::from_base64(1, "c2hvcnQ=").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Result, bail};
+
+use crate::serde::{Compression, Format};
+
+/// First bytes of every enveloped message. Read as the little endian size
+/// prefix of a compressed message it is about 1.3 GB, way over
+/// [`MAX_DECOMPRESSED_SIZE`](crate::serde::MAX_DECOMPRESSED_SIZE), so
+/// messages of peers without envelopes can't be mistaken for one.
+pub const MAGIC: [u8; 4] = *b"NRUN";
+
+/// Latest header layout this build understands.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+pub const HEADER_LEN: usize = MAGIC.len() + 4;
+
+/// Payload is compressed with a zstd [`Dictionary`](crate::serde::Dictionary).
+pub const FLAG_DICTIONARY: u8 = 0b0000_0001;
+
+/// Payload is encrypted with a [`Key`](crate::serde::Key).
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0010;
+
+/// Flags this build understands. Messages with any other flag are rejected,
+/// since a flag can change how the payload has to be read.
+const KNOWN_FLAGS: u8 = FLAG_DICTIONARY | FLAG_ENCRYPTED;
+
+impl Format {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::Json => 0,
+            Self::MessagePack => 1,
+            Self::Cbor => 2,
+            Self::Postcard => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => Self::Json,
+            1 => Self::MessagePack,
+            2 => Self::Cbor,
+            3 => Self::Postcard,
+            _ => bail!("Unknown codec id: {byte}"),
+        })
+    }
+}
+
+impl Compression {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+            Self::Lz4Frame => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => Self::None,
+            1 => Self::Lz4,
+            2 => Self::Zstd,
+            3 => Self::Lz4Frame,
+            _ => bail!("Unknown compression id: {byte}"),
+        })
+    }
+}
+
+/// Describes how the payload following it was encoded, so the receiver
+/// doesn't have to know it upfront.
+///
+/// `[magic 4][version u8][codec u8][compression u8][flags u8]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version:     u8,
+    pub format:      Format,
+    pub compression: Compression,
+    pub flags:       u8,
+}
+
+impl Header {
+    pub const fn new(format: Format, compression: Compression) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            format,
+            compression,
+            flags: 0,
+        }
+    }
+
+    pub const fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        [
+            MAGIC[0],
+            MAGIC[1],
+            MAGIC[2],
+            MAGIC[3],
+            self.version,
+            self.format.to_byte(),
+            self.compression.to_byte(),
+            self.flags,
+        ]
+    }
+
+    /// Splits the header off the payload. `None` for messages without an
+    /// envelope.
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, &[u8])>> {
+        if !data.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+
+        let Some((header, payload)) = data.split_at_checked(HEADER_LEN) else {
+            bail!("Truncated envelope header: {} bytes", data.len());
+        };
+
+        let [version, format, compression, flags] = [header[4], header[5], header[6], header[7]];
+
+        if version == 0 || version > ENVELOPE_VERSION {
+            bail!("Unsupported envelope version: {version}, supported up to {ENVELOPE_VERSION}");
+        }
+
+        if flags & !KNOWN_FLAGS != 0 {
+            bail!("Unsupported envelope flags: {flags:#010b}");
+        }
+
+        Ok(Some((
+            Self {
+                version,
+                format: Format::from_byte(format)?,
+                compression: Compression::from_byte(compression)?,
+                flags,
+            },
+            payload,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    use crate::serde::{Compression, ENVELOPE_VERSION, Format, HEADER_LEN, Header, MAGIC};
+
+    #[test]
+    fn test_header() -> Result<()> {
+        let header = Header::new(Format::Cbor, Compression::Lz4);
+
+        let mut data = header.to_bytes().to_vec();
+        data.extend([1, 2, 3]);
+
+        assert_eq!(Some((header, [1, 2, 3].as_slice())), Header::parse(&data)?);
+        assert_eq!(None, Header::parse(b"{\"json\":1}")?);
+        assert_eq!(None, Header::parse(&[])?);
+
+        assert!(Header::parse(&MAGIC).is_err());
+
+        let mut future = header;
+        future.version = ENVELOPE_VERSION + 1;
+        assert!(Header::parse(&future.to_bytes()).is_err());
+
+        let mut flagged = header.to_bytes();
+        flagged[HEADER_LEN - 1] = 0b1000_0000;
+        assert!(Header::parse(&flagged).is_err());
+
+        let mut unknown = header.to_bytes();
+        unknown[5] = 200;
+        assert!(Header::parse(&unknown).is_err());
+
+        Ok(())
+    }
+}
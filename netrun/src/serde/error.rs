@@ -0,0 +1,191 @@
+use std::{
+    any::type_name,
+    fmt::{Display, Formatter},
+};
+
+/// Bytes of the payload shown in [`DecodeError`].
+pub const PREVIEW_LEN: usize = 64;
+
+/// Step of decoding which failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStage {
+    /// Malformed [`Header`](crate::serde::Header).
+    Header,
+    Decrypt,
+    Decompress,
+    Utf8,
+    Parse,
+}
+
+impl Display for DecodeStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Header => "header",
+            Self::Decrypt => "decrypt",
+            Self::Decompress => "decompress",
+            Self::Utf8 => "UTF-8",
+            Self::Parse => "parse",
+        })
+    }
+}
+
+/// Why a message couldn't be decoded. The underlying error, like
+/// [`DecryptError`](crate::serde::DecryptError) or
+/// [`DecompressError`](crate::serde::DecompressError), is available with
+/// [`DecodeError::cause`].
+#[derive(Debug)]
+pub struct DecodeError {
+    pub type_name: &'static str,
+    /// Size of the message as received.
+    pub len:       usize,
+    pub stage:     DecodeStage,
+    /// Start of the data the stage failed on, as text when it is one and as
+    /// hex otherwise. Encrypted messages are never shown decrypted.
+    pub preview:   String,
+    pub source:    anyhow::Error,
+}
+
+impl DecodeError {
+    pub fn new<T>(stage: DecodeStage, len: usize, data: &[u8], source: impl Into<anyhow::Error>) -> Self {
+        Self {
+            type_name: type_name::<T>(),
+            len,
+            stage,
+            preview: preview(data),
+            source: source.into(),
+        }
+    }
+
+    /// Hides what was decrypted from `data`, since errors end up in logs.
+    /// The preview shows the encrypted message instead and parse errors,
+    /// which may quote values, lose their details.
+    pub(crate) fn encrypted(mut self, data: &[u8]) -> Self {
+        self.preview = preview(data);
+
+        if self.stage == DecodeStage::Parse {
+            self.source = anyhow::anyhow!("details of encrypted messages are hidden");
+        }
+
+        self
+    }
+
+    pub fn cause<E>(&self) -> Option<&E>
+    where E: Display + std::fmt::Debug + Send + Sync + 'static {
+        self.source.downcast_ref()
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to decode {} from {} bytes at {} stage: {}. Payload: {}",
+            self.type_name, self.len, self.stage, self.source, self.preview
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+fn preview(data: &[u8]) -> String {
+    let head = &data[..data.len().min(PREVIEW_LEN)];
+
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => Some(text),
+        // Cut in the middle of a character.
+        Err(err) if err.error_len().is_none() => {
+            Some(std::str::from_utf8(&head[..err.valid_up_to()]).expect("Valid up to here"))
+        }
+        Err(_) => None,
+    };
+
+    let mut preview = match text {
+        Some(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => format!("{text:?}"),
+        _ => head.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" "),
+    };
+
+    if data.len() > head.len() {
+        preview.push_str("...");
+    }
+
+    preview
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::serde::{
+        Codec, DecodeError, DecodeStage, DecompressError, Encoding, Format, Key, deserialize, serialize,
+    };
+
+    fn decode_error(err: &anyhow::Error) -> &DecodeError {
+        err.downcast_ref::<DecodeError>().expect("DecodeError")
+    }
+
+    #[test]
+    fn test_decode_error() -> anyhow::Result<()> {
+        let err = deserialize::<bool>(&serialize(1)?).unwrap_err();
+        let err = decode_error(&err);
+
+        assert_eq!("bool", err.type_name);
+        assert_eq!(DecodeStage::Parse, err.stage);
+        assert_eq!("\"1\"", err.preview);
+        assert_eq!(
+            "Failed to decode bool from 6 bytes at parse stage: invalid type: integer `1`, expected a \
+             boolean at line 1 column 1. Payload: \"1\"",
+            err.to_string()
+        );
+
+        let long = "long text ".repeat(20);
+        let err = deserialize::<u32>(&serialize(&long)?).unwrap_err();
+        let err = decode_error(&err);
+        assert_eq!(DecodeStage::Parse, err.stage);
+        assert!(err.preview.ends_with("..."));
+        assert!(err.preview.len() < long.len());
+
+        let json = Encoding::raw(Format::Json);
+        let err = json.decode::<String>(&[b'"', 0xFF, b'"']).unwrap_err();
+        let err = decode_error(&err);
+        assert_eq!(DecodeStage::Utf8, err.stage);
+        assert_eq!("22 ff 22", err.preview);
+
+        let err = Encoding::legacy().decode::<i32>(&[0xFF, 0xFF, 0xFF, 0xFF, 0]).unwrap_err();
+        let err = decode_error(&err);
+        assert_eq!(DecodeStage::Decompress, err.stage);
+        assert_eq!(5, err.len);
+        assert!(matches!(
+            err.cause::<DecompressError>(),
+            Some(DecompressError::TooLarge { .. })
+        ));
+
+        let enveloped = Encoding::compressed(Format::Json);
+        let mut data = enveloped.encode(&1)?;
+        data[4] = 100;
+        assert_eq!(
+            DecodeStage::Header,
+            decode_error(&enveloped.decode::<i32>(&data).unwrap_err()).stage
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_decode_error() -> anyhow::Result<()> {
+        let encoding = Encoding::compressed(Format::Json).encrypt(&Key::generate(70_020));
+        let data = encoding.encode("top secret")?;
+
+        let err = encoding.decode::<i32>(&data).unwrap_err();
+        let err = decode_error(&err);
+
+        assert_eq!(DecodeStage::Parse, err.stage);
+        assert_eq!(super::preview(&data), err.preview);
+        assert!(!err.to_string().contains("secret"), "{err}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,613 @@
+mod compression;
+mod crypto;
+mod envelope;
+mod error;
+mod schema;
+mod scope;
+#[cfg(not_wasm)]
+mod stream;
+
+use std::{
+    io::{BufReader, Read, Write},
+    sync::LazyLock,
+};
+
+use anyhow::{Result, bail};
+pub use compression::*;
+pub use crypto::*;
+pub use envelope::*;
+pub use error::*;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use parking_lot::RwLock;
+pub use schema::*;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+#[cfg(not_wasm)]
+pub use stream::*;
+
+/// Turns serde values into bytes and back.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Structs are encoded as maps, so fields can be added without breaking
+/// older peers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        ciborium::into_writer(val, &mut data)?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(ciborium::from_reader(data)?)
+    }
+}
+
+/// The most compact and the fastest one. Not self describing, so it can't
+/// decode types which rely on `deserialize_any`, like `serde_json::Value`
+/// or untagged enums.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(postcard::from_bytes(data)?)
+    }
+}
+
+/// Wraps a codec with LZ4 compression. Decompression is limited to
+/// [`MAX_DECOMPRESSED_SIZE`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4<C>(pub C);
+
+impl<C: Codec> Codec for Lz4<C> {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        Ok(compress(&self.0.encode(val)?))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        self.0.decode(&decompress(data)?)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+    Postcard,
+}
+
+/// Codec picked at runtime.
+///
+/// Messages start with a [`Header`] naming their format and compression, so
+/// the receiver decodes them whatever encoding it uses itself. Messages
+/// without one are decoded with the receiver's encoding. The default is
+/// [`Encoding::legacy`] until changed with [`Encoding::set_default`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Encoding {
+    pub format:      Format,
+    pub compression: Compression,
+    /// Zstd level.
+    pub level:       i32,
+    /// Smaller messages are sent uncompressed.
+    pub threshold:   usize,
+    /// Id of a registered zstd [`Dictionary`].
+    pub dictionary:  Option<u32>,
+    /// Id of a registered [`Key`](crate::serde::Key). Messages are encrypted
+    /// with it and unencrypted ones are rejected.
+    pub key:         Option<u32>,
+    /// Larger compressed messages are rejected with [`DecompressError`].
+    pub max_size:    usize,
+    /// Whether encoded messages start with a [`Header`].
+    pub envelope:    bool,
+}
+
+static DEFAULT: LazyLock<RwLock<Encoding>> = LazyLock::new(|| RwLock::new(Encoding::legacy()));
+
+impl Default for Encoding {
+    fn default() -> Self {
+        *DEFAULT.read()
+    }
+}
+
+impl Encoding {
+    /// LZ4.
+    pub const fn compressed(format: Format) -> Self {
+        Self::raw(format).compression(Compression::Lz4)
+    }
+
+    pub const fn raw(format: Format) -> Self {
+        Self {
+            format,
+            compression: Compression::None,
+            level: ZSTD_LEVEL,
+            threshold: COMPRESSION_THRESHOLD,
+            dictionary: None,
+            key: None,
+            max_size: MAX_DECOMPRESSED_SIZE,
+            envelope: true,
+        }
+    }
+
+    pub const fn zstd(format: Format, level: i32) -> Self {
+        let mut encoding = Self::raw(format).compression(Compression::Zstd);
+        encoding.level = level;
+        encoding
+    }
+
+    /// Peers released before envelopes only understand compressed JSON
+    /// without a header.
+    pub const fn legacy() -> Self {
+        Self::compressed(Format::Json).envelope(false)
+    }
+
+    /// Changes what [`Encoding::default`] returns from now on, e.g. for
+    /// connections created later.
+    ///
+    /// Envelopes are rolled out in two phases. First every peer is updated,
+    /// still sending [`Encoding::legacy`] but decoding messages with and
+    /// without a header. Once no old peers are left, they switch to
+    /// enveloped messages with
+    /// `Encoding::set_default(Encoding::compressed(Format::Json))`.
+    pub fn set_default(encoding: Encoding) {
+        *DEFAULT.write() = encoding;
+    }
+
+    pub const fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Without envelope every message is compressed, since the receiver
+    /// couldn't tell which ones are.
+    pub const fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Compresses with the dictionary, only used by zstd. Registers it, so
+    /// this process can also decode messages which use it.
+    pub fn dictionary(mut self, dictionary: &Dictionary) -> Self {
+        dictionary.register();
+        self.dictionary = Some(dictionary.id());
+        self
+    }
+
+    /// Registers the key, so this process can also decode messages which use
+    /// it. Turns the envelope on, encryption needs it.
+    #[cfg(not_wasm)]
+    pub fn encrypt(mut self, key: &Key) -> Self {
+        key.register();
+        self.key = Some(key.id());
+        self.envelope = true;
+        self
+    }
+
+    pub const fn envelope(mut self, envelope: bool) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    pub const fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Appends the encoded message to `out`, so the buffer can be reused.
+    /// Serializes straight into it, without intermediate copies unless the
+    /// message is compressed in one block or encrypted.
+    pub fn encode_into<T: Serialize + ?Sized>(&self, val: &T, out: &mut Vec<u8>) -> Result<()> {
+        if self.key.is_some() && !self.envelope {
+            bail!("Encryption needs the envelope");
+        }
+
+        let start = out.len();
+        if self.envelope {
+            out.extend([0; HEADER_LEN]);
+        }
+        let body = out.len();
+
+        let (compression, mut flags) = if self.compression == Compression::Lz4Frame {
+            let mut encoder = FrameEncoder::new(&mut *out);
+            self.write_format(val, &mut encoder)?;
+            encoder.finish()?;
+            (Compression::Lz4Frame, 0)
+        } else {
+            self.write_format(val, &mut *out)?;
+
+            match self.compress(&out[body..])? {
+                Some((compressed, flags)) => {
+                    out.truncate(body);
+                    out.extend(compressed);
+                    (self.compression, flags)
+                }
+                None => (Compression::None, 0),
+            }
+        };
+
+        if !self.envelope {
+            return Ok(());
+        }
+
+        if self.key.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+
+        let header = Header {
+            flags,
+            ..Header::new(self.format, compression)
+        }
+        .to_bytes();
+
+        out[start..body].copy_from_slice(&header);
+
+        if let Some(id) = self.key {
+            let sealed = seal(id, &header, &out[body..])?;
+            out.truncate(body);
+            out.extend(sealed);
+        }
+
+        Ok(())
+    }
+
+    fn write_format<T: Serialize + ?Sized>(self, val: &T, mut writer: impl Write) -> Result<()> {
+        match self.format {
+            Format::Json => serde_json::to_writer(writer, val)?,
+            Format::MessagePack => rmp_serde::encode::write_named(&mut writer, val)?,
+            Format::Cbor => ciborium::into_writer(val, writer)?,
+            Format::Postcard => {
+                postcard::to_io(val, writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `len` is the size of the whole message, for errors.
+    fn decode_format<T: DeserializeOwned>(self, data: &[u8], len: usize) -> Result<T, DecodeError> {
+        let parse = |err| DecodeError::new::<T>(DecodeStage::Parse, len, data, err);
+
+        match self.format {
+            Format::Json => decode_json(data, len),
+            Format::MessagePack => MessagePack.decode(data).map_err(parse),
+            Format::Cbor => Cbor.decode(data).map_err(parse),
+            Format::Postcard => Postcard.decode(data).map_err(parse),
+        }
+    }
+
+    fn read_format<T: DeserializeOwned>(self, mut reader: impl Read) -> Result<T> {
+        Ok(match self.format {
+            Format::Json => serde_json::from_reader(reader)?,
+            Format::MessagePack => rmp_serde::from_read(reader)?,
+            Format::Cbor => ciborium::from_reader(reader)?,
+            Format::Postcard => {
+                let mut data = vec![];
+                reader.read_to_end(&mut data)?;
+                postcard::from_bytes(&data)?
+            }
+        })
+    }
+
+    fn decode_payload<T: DeserializeOwned>(&self, data: &[u8], len: usize) -> Result<T, DecodeError> {
+        let decompress = |err| DecodeError::new::<T>(DecodeStage::Decompress, len, data, err);
+
+        match self.compression {
+            Compression::None => self.decode_format(data, len),
+            Compression::Lz4 => self.decode_format(
+                &decompress_with_limit(data, self.max_size).map_err(decompress)?,
+                len,
+            ),
+            // Parsed while decompressing, the whole message is never in memory.
+            Compression::Lz4Frame => {
+                let mut reader = FrameReader {
+                    decoder: FrameDecoder::new(data).take(self.max_size as u64),
+                    failed:  false,
+                };
+
+                self.read_format(BufReader::new(&mut reader)).map_err(|err| {
+                    let fail = |stage, err| DecodeError::new::<T>(stage, len, data, err);
+
+                    if reader.failed {
+                        fail(DecodeStage::Decompress, err)
+                    } else if reader.decoder.limit() == 0 {
+                        let limit = self.max_size;
+                        fail(
+                            DecodeStage::Decompress,
+                            DecompressError::TooLarge { size: limit, limit }.into(),
+                        )
+                    } else {
+                        fail(DecodeStage::Parse, err)
+                    }
+                })
+            }
+            Compression::Zstd => {
+                self.decode_format(&decompress_zstd(data, self.max_size).map_err(decompress)?, len)
+            }
+        }
+    }
+
+    /// Compressed data and header flags, `None` when it's better sent as is.
+    fn compress(&self, data: &[u8]) -> Result<Option<(Vec<u8>, u8)>> {
+        if self.compression == Compression::None {
+            return Ok(None);
+        }
+
+        if self.envelope && data.len() < self.threshold {
+            CompressionStats::record_skipped();
+            return Ok(None);
+        }
+
+        let (compressed, flags) = match (self.compression, self.dictionary) {
+            (Compression::Zstd, Some(id)) => {
+                let dictionary = Dictionary::registered(id).ok_or(DecompressError::UnknownDictionary(id))?;
+                (
+                    compress_zstd(data, self.level, Some(&dictionary))?,
+                    FLAG_DICTIONARY,
+                )
+            }
+            (Compression::Zstd, None) => (compress_zstd(data, self.level, None)?, 0),
+            _ => (compress(data), 0),
+        };
+
+        if self.envelope && compressed.len() >= data.len() {
+            CompressionStats::record_skipped();
+            return Ok(None);
+        }
+
+        CompressionStats::record(data.len(), compressed.len());
+
+        Ok(Some((compressed, flags)))
+    }
+}
+
+impl Codec for Encoding {
+    fn encode<T: Serialize + ?Sized>(&self, val: &T) -> Result<Vec<u8>> {
+        let mut message = vec![];
+        self.encode_into(val, &mut message)?;
+        Ok(message)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        let len = data.len();
+        let fail = |stage, err| DecodeError::new::<T>(stage, len, data, err);
+
+        let Some((header, payload)) = Header::parse(data).map_err(|err| fail(DecodeStage::Header, err))?
+        else {
+            if self.key.is_some() {
+                return Err(fail(DecodeStage::Decrypt, DecryptError::Unencrypted.into()).into());
+            }
+            return Ok(self.decode_payload(data, len)?);
+        };
+
+        let encoding = Self {
+            format: header.format,
+            compression: header.compression,
+            ..*self
+        };
+
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            let payload =
+                open(&data[..HEADER_LEN], payload).map_err(|err| fail(DecodeStage::Decrypt, err.into()))?;
+            return Ok(encoding.decode_payload(&payload, len).map_err(|err| err.encrypted(data))?);
+        }
+
+        if self.key.is_some() {
+            return Err(fail(DecodeStage::Decrypt, DecryptError::Unencrypted.into()).into());
+        }
+
+        Ok(encoding.decode_payload(payload, len)?)
+    }
+}
+
+/// Tells decompression failures of a streamed frame apart from parse ones.
+struct FrameReader<R> {
+    decoder: R,
+    failed:  bool,
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.decoder.read(buf).inspect_err(|_| self.failed = true)
+    }
+}
+
+/// JSON with UTF-8 checked first, so invalid text isn't reported as a
+/// syntax error. `len` is the size of the whole message, for errors.
+pub(crate) fn decode_json<T: DeserializeOwned>(data: &[u8], len: usize) -> Result<T, DecodeError> {
+    let text =
+        std::str::from_utf8(data).map_err(|err| DecodeError::new::<T>(DecodeStage::Utf8, len, data, err))?;
+    serde_json::from_str(text).map_err(|err| DecodeError::new::<T>(DecodeStage::Parse, len, data, err))
+}
+
+/// Uses the default [`Encoding`].
+pub fn serialize(val: impl Serialize) -> Result<Vec<u8>> {
+    Encoding::default().encode(&val)
+}
+
+pub fn deserialize<T: DeserializeOwned>(buff: &[u8]) -> Result<T> {
+    Encoding::default().decode(buff)
+}
+
+#[cfg(test)]
+mod test {
+
+    use anyhow::Result;
+    use serde::{Deserialize, Serialize};
+
+    use crate::serde::{
+        Codec, Compression, CompressionStats, DecodeError, DecompressError, Encoding, Format, HEADER_LEN,
+        Header, deserialize, serialize,
+    };
+
+    #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+    struct User {
+        age:    i32,
+        height: f32,
+        name:   String,
+    }
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        let users = vec![
+            User {
+                age:    55,
+                height: 1.9,
+                name:   "Roma".to_owned(),
+            };
+            100
+        ];
+
+        let string = serde_json::to_string(&users)?;
+
+        let ser = serialize(&users)?;
+
+        assert_eq!(string.len(), 3801);
+        assert_eq!(ser.len(), 69);
+
+        let de: Vec<User> = deserialize(&ser)?;
+
+        assert_eq!(users, de);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encodings() -> Result<()> {
+        let users = vec![
+            User {
+                age:    55,
+                height: 1.9,
+                name:   "Roma".to_owned(),
+            };
+            100
+        ];
+
+        let json = Encoding::raw(Format::Json).encode(&users)?.len();
+
+        for format in [Format::Json, Format::MessagePack, Format::Cbor, Format::Postcard] {
+            for encoding in [
+                Encoding::raw(format),
+                Encoding::compressed(format),
+                Encoding::raw(format).compression(Compression::Lz4Frame),
+            ] {
+                let data = encoding.encode(&users)?;
+                assert_eq!(users, encoding.decode::<Vec<User>>(&data)?, "{encoding:?}");
+
+                if encoding.compression != Compression::None {
+                    assert!(data.len() < json / 10, "{encoding:?}: {}", data.len());
+                }
+            }
+        }
+
+        assert!(Encoding::raw(Format::Postcard).encode(&users)?.len() < json / 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_envelope() -> Result<()> {
+        let receiver = Encoding::raw(Format::Postcard);
+
+        for format in [Format::Json, Format::MessagePack, Format::Cbor, Format::Postcard] {
+            for sender in [Encoding::raw(format), Encoding::compressed(format)] {
+                let data = sender.encode(&vec![1, 2, 3])?;
+                assert_eq!(vec![1, 2, 3], receiver.decode::<Vec<i32>>(&data)?, "{sender:?}");
+            }
+        }
+
+        assert_eq!(Encoding::legacy(), Encoding::default());
+
+        let legacy = Encoding::legacy().encode(&5)?;
+        assert_eq!(5, Encoding::default().decode::<i32>(&legacy)?);
+        assert!(Encoding::raw(Format::Json).decode::<i32>(&legacy).is_err());
+
+        let mut future = Encoding::compressed(Format::Json).encode(&5)?;
+        future[4] += 1;
+        assert!(Encoding::default().decode::<i32>(&future).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_policy() -> Result<()> {
+        let small = Encoding::compressed(Format::Json).encode(&5)?;
+        assert_eq!(HEADER_LEN + 1, small.len());
+        assert_eq!(Compression::None, Header::parse(&small)?.unwrap().0.compression);
+
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let data = Encoding::compressed(Format::Postcard).encode(&noise)?;
+        assert_eq!(Compression::None, Header::parse(&data)?.unwrap().0.compression);
+
+        let before = CompressionStats::get();
+
+        let users = vec![
+            User {
+                age:    55,
+                height: 1.9,
+                name:   "Roma".to_owned(),
+            };
+            100
+        ];
+
+        let data = Encoding::zstd(Format::Json, 19).encode(&users)?;
+        assert_eq!(Compression::Zstd, Header::parse(&data)?.unwrap().0.compression);
+        assert_eq!(users, Encoding::default().decode::<Vec<User>>(&data)?);
+
+        let stats = CompressionStats::get();
+        assert!(stats.compressed > before.compressed);
+        assert!(stats.input_bytes - before.input_bytes >= 3801);
+        assert!(stats.ratio() < 1.0);
+
+        let err = Encoding::default()
+            .max_size(10)
+            .decode::<Vec<u8>>(&serialize(vec![0u8; 100])?)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>().and_then(DecodeError::cause),
+            Some(DecompressError::TooLarge { limit: 10, .. })
+        ));
+
+        Ok(())
+    }
+}
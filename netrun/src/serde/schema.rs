@@ -0,0 +1,332 @@
+use std::{collections::BTreeMap, fmt::Formatter, marker::PhantomData, sync::LazyLock};
+
+use anyhow::{Result, bail};
+use parking_lot::RwLock;
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, DeserializeOwned, SeqAccess, Visitor},
+    ser::SerializeTuple,
+};
+use serde_json::Value;
+
+/// Wire type with a schema version.
+///
+/// Payloads of older versions are migrated with [`Schema::upgrade`] one
+/// version at a time, so any version from [`Schema::MIN_VERSION`] up to
+/// [`Schema::VERSION`] can be decoded. Migrations work on a [`Value`] and
+/// need a self describing format, not
+/// [`Format::Postcard`](crate::serde::Format::Postcard).
+pub trait Schema: Serialize + DeserializeOwned {
+    /// Stable name peers know the type by.
+    const NAME: &'static str;
+    const VERSION: u32;
+    /// Oldest version which can still be upgraded.
+    const MIN_VERSION: u32 = Self::VERSION;
+
+    /// Migrates a payload of `version` to `version + 1`.
+    fn upgrade(version: u32, _value: Value) -> Result<Value> {
+        bail!("{} can't be upgraded from version {version}", Self::NAME)
+    }
+}
+
+/// Sends `T` along with its schema version and upgrades older payloads on
+/// receive. Use it as the message type:
+///
+/// ```ignore
+/// let client = Client::<Versioned<User>, Versioned<User>>::connect(addr).await?;
+/// client.send(user).await?;
+/// let user = client.receive().await?.into_inner();
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Versioned<T>(pub T);
+
+impl<T> Versioned<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Versioned<T> {
+    fn from(val: T) -> Self {
+        Self(val)
+    }
+}
+
+impl<T: Schema> Serialize for Versioned<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&T::VERSION)?;
+        tuple.serialize_element(&self.0)?;
+        tuple.end()
+    }
+}
+
+impl<'de, T: Schema> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, VersionedVisitor(PhantomData))
+    }
+}
+
+struct VersionedVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Schema> Visitor<'de> for VersionedVisitor<T> {
+    type Value = Versioned<T>;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "versioned {}", T::NAME)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+        let version: u32 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+
+        if version > T::VERSION {
+            return Err(de::Error::custom(format!(
+                "{} version {version} is newer than supported {}",
+                T::NAME,
+                T::VERSION
+            )));
+        }
+
+        // Current version is decoded directly, any format works.
+        if version == T::VERSION {
+            let val = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+            return Ok(Versioned(val));
+        }
+
+        let value: Value = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+        migrate(version, value).map(Versioned).map_err(de::Error::custom)
+    }
+}
+
+fn migrate<T: Schema>(mut version: u32, mut value: Value) -> Result<T> {
+    if version < T::MIN_VERSION {
+        bail!(
+            "{} version {version} is not supported anymore, oldest is {}",
+            T::NAME,
+            T::MIN_VERSION
+        );
+    }
+
+    while version < T::VERSION {
+        value = T::upgrade(version, value)?;
+        version += 1;
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Versions of a [`Schema`] this process can decode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaInfo {
+    pub name:        String,
+    pub version:     u32,
+    pub min_version: u32,
+}
+
+impl SchemaInfo {
+    pub fn of<T: Schema>() -> Self {
+        Self {
+            name:        T::NAME.to_string(),
+            version:     T::VERSION,
+            min_version: T::MIN_VERSION,
+        }
+    }
+
+    pub fn reads(&self, version: u32) -> bool {
+        (self.min_version..=self.version).contains(&version)
+    }
+}
+
+static SCHEMAS: LazyLock<RwLock<BTreeMap<&'static str, SchemaInfo>>> = LazyLock::new(RwLock::default);
+
+/// Adds `T` to the schemas reported to peers, see
+/// [`Client::exchange_schemas`](crate::Client::exchange_schemas).
+pub fn register_schema<T: Schema>() {
+    SCHEMAS.write().insert(T::NAME, SchemaInfo::of::<T>());
+}
+
+pub fn registered_schemas() -> Vec<SchemaInfo> {
+    SCHEMAS.read().values().cloned().collect()
+}
+
+/// Local and remote schemas of the same name where one peer can't decode
+/// the other's messages. Schemas known to one side only are skipped.
+pub fn incompatible_schemas(local: &[SchemaInfo], remote: &[SchemaInfo]) -> Vec<(SchemaInfo, SchemaInfo)> {
+    local
+        .iter()
+        .filter_map(|local| {
+            let remote = remote.iter().find(|remote| remote.name == local.name)?;
+            (!local.reads(remote.version) || !remote.reads(local.version))
+                .then(|| (local.clone(), remote.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, slice::from_ref};
+
+    use anyhow::{Result, bail};
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use test_log::test;
+
+    use crate::{
+        Client, Server,
+        serde::{
+            Codec, Encoding, Format, Schema, SchemaInfo, Versioned, incompatible_schemas, register_schema,
+            registered_schemas,
+        },
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct UserV1 {
+        name: String,
+    }
+
+    impl Schema for UserV1 {
+        const NAME: &'static str = "user";
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct UserV2 {
+        first_name: String,
+    }
+
+    impl Schema for UserV2 {
+        const NAME: &'static str = "user";
+        const VERSION: u32 = 2;
+        const MIN_VERSION: u32 = 1;
+
+        fn upgrade(version: u32, value: Value) -> Result<Value> {
+            let Value::Object(mut user) = value else {
+                bail!("User is not an object");
+            };
+
+            match version {
+                1 => {
+                    let name = user.remove("name").unwrap_or_default();
+                    user.insert("first_name".to_string(), name);
+                }
+                _ => bail!("Unknown user version: {version}"),
+            }
+
+            Ok(Value::Object(user))
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        first_name: String,
+        age:        u32,
+    }
+
+    impl Schema for User {
+        const NAME: &'static str = "user";
+        const VERSION: u32 = 3;
+        const MIN_VERSION: u32 = 1;
+
+        fn upgrade(version: u32, mut value: Value) -> Result<Value> {
+            match version {
+                1 => UserV2::upgrade(version, value),
+                2 => {
+                    value["age"] = 0.into();
+                    Ok(value)
+                }
+                _ => bail!("Unknown user version: {version}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_versioned() -> Result<()> {
+        for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+            let encoding = Encoding::raw(format);
+
+            let old = encoding.encode(&Versioned(UserV1 {
+                name: "Roma".to_string(),
+            }))?;
+
+            let upgraded = encoding.decode::<Versioned<User>>(&old)?.into_inner();
+            assert_eq!(
+                User {
+                    first_name: "Roma".to_string(),
+                    age:        0,
+                },
+                upgraded,
+                "{format:?}"
+            );
+
+            let new = encoding.encode(&Versioned(upgraded))?;
+            assert!(encoding.decode::<Versioned<UserV2>>(&new).is_err());
+        }
+
+        // Current version doesn't need a self describing format.
+        let encoding = Encoding::raw(Format::Postcard);
+        let user = User {
+            first_name: "Vlad".to_string(),
+            age:        30,
+        };
+        let data = encoding.encode(&Versioned::from(user))?;
+        assert_eq!(30, encoding.decode::<Versioned<User>>(&data)?.0.age);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_incompatible_schemas() {
+        let v1 = SchemaInfo::of::<UserV1>();
+        let v2 = SchemaInfo::of::<UserV2>();
+        let v3 = SchemaInfo::of::<User>();
+
+        assert!(v3.reads(1) && v3.reads(3) && !v3.reads(4));
+
+        // Old peers can't read newer messages.
+        assert_eq!(
+            vec![(v3.clone(), v1.clone())],
+            incompatible_schemas(from_ref(&v3), &[v1])
+        );
+        assert_eq!(
+            Vec::<(SchemaInfo, SchemaInfo)>::new(),
+            incompatible_schemas(from_ref(&v3), from_ref(&v3))
+        );
+
+        let other = SchemaInfo {
+            name:        "other".to_string(),
+            version:     1,
+            min_version: 1,
+        };
+        assert_eq!(
+            vec![(v3.clone(), v2.clone())],
+            incompatible_schemas(&[v3, other], &[v2])
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn test_exchange_schemas() -> Result<()> {
+        register_schema::<User>();
+
+        let server = Server::<Versioned<User>, Versioned<User>>::start(57830).await?;
+        let client =
+            Client::<Versioned<UserV1>, Versioned<UserV1>>::connect((Ipv4Addr::LOCALHOST, 57830)).await?;
+        let connection = server.wait_for_new_connection().await;
+
+        let (remote, local) = tokio::try_join!(client.exchange_schemas(), connection.exchange_schemas())?;
+
+        assert_eq!(registered_schemas(), remote);
+        assert_eq!(registered_schemas(), local);
+
+        client
+            .send(UserV1 {
+                name: "Roma".to_string(),
+            })
+            .await?;
+
+        assert_eq!("Roma", connection.receive().await?.0.first_name);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use std::{cell::RefCell, thread::LocalKey};
+
+/// Sets a thread local for as long as it lives and restores the previous
+/// value when dropped, also while unwinding.
+///
+/// Used to hand per connection state to encoding and decoding, which run
+/// synchronously, so the value can't leak into another task.
+pub(crate) struct Scoped<T: 'static> {
+    key:      &'static LocalKey<RefCell<Option<T>>>,
+    previous: Option<T>,
+}
+
+impl<T: 'static> Scoped<T> {
+    pub fn set(key: &'static LocalKey<RefCell<Option<T>>>, value: T) -> Self {
+        Self {
+            key,
+            previous: key.replace(Some(value)),
+        }
+    }
+}
+
+impl<T: 'static> Drop for Scoped<T> {
+    fn drop(&mut self) {
+        self.key.set(self.previous.take());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, panic::catch_unwind};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::serde::scope::Scoped;
+
+    thread_local! {
+        static VALUE: RefCell<Option<u32>> = const { RefCell::new(None) };
+    }
+
+    #[test]
+    fn test_scoped() {
+        {
+            let _outer = Scoped::set(&VALUE, 1);
+            {
+                let _inner = Scoped::set(&VALUE, 2);
+                assert_eq!(Some(2), VALUE.with_borrow(Clone::clone));
+            }
+            assert_eq!(Some(1), VALUE.with_borrow(Clone::clone));
+
+            _ = catch_unwind(|| {
+                let _scoped = Scoped::set(&VALUE, 3);
+                panic!("Decoding failed");
+            });
+            assert_eq!(Some(1), VALUE.with_borrow(Clone::clone));
+        }
+        assert_eq!(None, VALUE.with_borrow(Clone::clone));
+    }
+}
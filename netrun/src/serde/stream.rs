@@ -0,0 +1,211 @@
+use anyhow::{Result, bail};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::serde::{Codec, Encoding};
+
+const LEN_SIZE: usize = size_of::<u32>();
+
+/// Writes messages as `[len u32][message]` frames.
+///
+/// Messages are encoded into a buffer which is reused, so after the first
+/// few messages writing doesn't allocate. The length goes first, so each
+/// encoded message is held in memory whole before it's written, and can't
+/// be over 4 GB. With
+/// [`Compression::Lz4Frame`](crate::serde::Compression::Lz4Frame) that's
+/// the compressed message, the uncompressed one is never held in memory.
+pub struct MessageWriter<W> {
+    writer:   W,
+    encoding: Encoding,
+    buffer:   Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub fn new(writer: W, encoding: Encoding) -> Self {
+        Self {
+            writer,
+            encoding,
+            buffer: vec![],
+        }
+    }
+
+    pub async fn write<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.extend([0; LEN_SIZE]);
+        self.encoding.encode_into(val, &mut self.buffer)?;
+
+        let Ok(len) = u32::try_from(self.buffer.len() - LEN_SIZE) else {
+            bail!("Message of {} bytes is too large", self.buffer.len());
+        };
+        self.buffer[..LEN_SIZE].copy_from_slice(&len.to_le_bytes());
+
+        self.writer.write_all(&self.buffer).await?;
+
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush().await?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads frames written by [`MessageWriter`]. Decodes any [`Encoding`] the
+/// messages carry in their header.
+pub struct MessageReader<R> {
+    reader:   R,
+    encoding: Encoding,
+    buffer:   Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R, encoding: Encoding) -> Self {
+        Self {
+            reader,
+            encoding,
+            buffer: vec![],
+        }
+    }
+
+    /// `None` once the stream ends between messages, ending anywhere else is
+    /// an error. Messages over [`Encoding::max_size`] are rejected before
+    /// reading them.
+    pub async fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let mut len = [0; LEN_SIZE];
+        let mut filled = 0;
+
+        while filled < LEN_SIZE {
+            match self.reader.read(&mut len[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => bail!("Stream ended after {filled} bytes of a message length"),
+                read => filled += read,
+            }
+        }
+
+        let len = u32::from_le_bytes(len) as usize;
+
+        if len > self.encoding.max_size {
+            bail!(
+                "Message of {len} bytes exceeds limit of {} bytes",
+                self.encoding.max_size
+            );
+        }
+
+        self.buffer.resize(len, 0);
+        self.reader.read_exact(&mut self.buffer).await?;
+
+        self.encoding.decode(&self.buffer).map(Some)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+    use tokio::io::{AsyncWriteExt, duplex};
+
+    use crate::serde::{Compression, Encoding, Format, MessageReader, MessageWriter};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id:     u64,
+        name:   String,
+        values: Vec<f64>,
+    }
+
+    fn sample(id: u64) -> Sample {
+        Sample {
+            id,
+            name: format!("sample {id}"),
+            values: (0..10_000).map(|i| f64::from(i) / 4.0).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream() -> Result<()> {
+        let (client, server) = duplex(64 * 1024);
+
+        let frame = Encoding::raw(Format::Json).compression(Compression::Lz4Frame);
+
+        let mut writer = MessageWriter::new(client, frame);
+        let mut reader = MessageReader::new(server, Encoding::default());
+
+        let write = tokio::spawn(async move {
+            for id in 0..5 {
+                writer.write(&sample(id)).await?;
+            }
+
+            writer.encoding = Encoding::zstd(Format::Cbor, 5);
+            writer.write(&sample(5)).await?;
+
+            writer.encoding = Encoding::raw(Format::Postcard);
+            writer.write(&sample(6)).await?;
+            let capacity = writer.buffer.capacity();
+            writer.write(&sample(7)).await?;
+            writer.flush().await?;
+
+            assert_eq!(capacity, writer.buffer.capacity());
+
+            anyhow::Ok(())
+        });
+
+        for id in 0..8 {
+            assert_eq!(Some(sample(id)), reader.read().await?);
+        }
+
+        write.await??;
+
+        assert_eq!(None, reader.read::<Sample>().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_limits() -> Result<()> {
+        let frame = Encoding::raw(Format::Json).compression(Compression::Lz4Frame);
+
+        let zeros = Sample {
+            id:     0,
+            name:   String::new(),
+            values: vec![0.0; 10_000],
+        };
+
+        let (client, server) = duplex(1024 * 1024);
+        let mut writer = MessageWriter::new(client, frame);
+        writer.write(&zeros).await?;
+        writer.write(&zeros).await?;
+
+        // Compressed it fits, decompressed it doesn't.
+        let mut reader = MessageReader::new(server, Encoding::default().max_size(20_000));
+        assert!(reader.read::<Sample>().await.is_err());
+
+        // Frame length over the limit.
+        let mut reader = MessageReader::new(reader.into_inner(), Encoding::default().max_size(100));
+        assert!(reader.read::<Sample>().await.is_err());
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(&[10, 0, 0, 0, 1, 2]).await?;
+        drop(client);
+
+        let mut reader = MessageReader::new(server, Encoding::default());
+        assert!(reader.read::<Sample>().await.is_err());
+
+        // Ends inside the length.
+        let (mut client, server) = duplex(1024);
+        client.write_all(&[10, 0]).await?;
+        drop(client);
+
+        let mut reader = MessageReader::new(server, Encoding::default());
+        assert!(reader.read::<Sample>().await.is_err());
+
+        Ok(())
+    }
+}
@@ -55,6 +55,10 @@ impl System {
         result
     }
 
+    pub fn hostname() -> String {
+        sysinfo::System::host_name().unwrap_or_else(|| "Unknown".to_string())
+    }
+
     pub fn get_info() -> Self {
         let mut sys = sysinfo::System::new_all();
 
@@ -66,7 +70,7 @@ impl System {
         let unknown = || "Unknown".to_string();
 
         Self {
-            hostname:    sysinfo::System::host_name().unwrap_or_else(unknown),
+            hostname:    Self::hostname(),
             os:          sysinfo::System::name().unwrap_or_else(unknown),
             os_version:  sysinfo::System::os_version().unwrap_or_else(unknown),
             system_name: sysinfo::System::long_os_version().unwrap_or_else(unknown),
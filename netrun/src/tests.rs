@@ -1,6 +1,41 @@
 #![cfg(test)]
 
 use anyhow::Result;
+#[cfg(not_wasm)]
+pub(crate) use relay::relay;
+
+#[cfg(not_wasm)]
+mod relay {
+    use std::net::Ipv4Addr;
+
+    use anyhow::Result;
+    use tokio::{
+        io::copy_bidirectional,
+        net::{TcpListener, TcpStream},
+        spawn,
+        task::{JoinHandle, JoinSet},
+    };
+
+    /// Forwards connections to the target port until aborted. Aborting drops
+    /// all forwarded connections, which simulates a network failure.
+    pub(crate) async fn relay(port: u16, target: u16) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+
+        Ok(spawn(async move {
+            let mut connections = JoinSet::new();
+
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let Ok(mut outbound) = TcpStream::connect((Ipv4Addr::LOCALHOST, target)).await else {
+                    continue;
+                };
+
+                connections.spawn(async move {
+                    _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        }))
+    }
+}
 
 #[test]
 fn test_local_ip() -> Result<()> {
@@ -3,3 +3,7 @@ mod req;
 
 pub use rep::*;
 pub use req::*;
+
+/// First frame of a reply telling the request failed. The second one is the
+/// error message.
+const ERROR: &str = "netrun.error";
@@ -1,18 +1,21 @@
-use std::any::type_name;
+use std::{any::type_name, sync::Arc};
 
 use anyhow::{Result, anyhow};
 use hreads::spawn;
 use log::error;
+use parking_lot::Mutex;
 use serde::{Serialize, de::DeserializeOwned};
-use zeromq::{RepSocket, Socket, SocketRecv, SocketSend};
+use zeromq::{RepSocket, Socket, SocketRecv, SocketSend, ZmqMessage};
 
 use crate::{
     Function,
-    serde::{deserialize, serialize},
+    serde::{Codec, Encoding, MAX_SENDERS, ReplayWindows},
+    zmq::ERROR,
 };
 
 pub struct Rep<In: DeserializeOwned + 'static, Out: Serialize + 'static> {
-    receive: Function<In, Out>,
+    receive:  Function<In, Out>,
+    encoding: Arc<Mutex<Encoding>>,
 }
 
 impl<In: DeserializeOwned + 'static, Out: Serialize + 'static> Rep<In, Out> {
@@ -23,16 +26,35 @@ impl<In: DeserializeOwned + 'static, Out: Serialize + 'static> Rep<In, Out> {
         let function = Function::default();
         let receive = function.clone();
 
+        let encoding = Arc::new(Mutex::new(Encoding::default()));
+        let enc = encoding.clone();
+
+        // Requests from all requesters arrive here in order.
+        let replays = Arc::new(ReplayWindows::new(MAX_SENDERS));
+
         spawn(async move {
             loop {
                 let result: Result<()> = async {
                     let data: Vec<u8> = socket.recv().await?.try_into().map_err(|err| anyhow!("{err}"))?;
 
-                    let input: In = deserialize(&data)?;
-                    let output = function.call(input);
-                    let out_data = serialize(output)?;
+                    let encoding = *enc.lock();
+
+                    let reply = replays
+                        .check(|| encoding.decode(&data))
+                        .and_then(|input: In| encoding.encode(&function.call(input)));
 
-                    socket.send(out_data.into()).await?;
+                    // Every request needs a reply, or the requester waits forever.
+                    let reply = match reply {
+                        Ok(data) => ZmqMessage::from(data),
+                        Err(err) => {
+                            error!("{}: {err}", type_name::<Self>());
+                            let mut reply = ZmqMessage::from(ERROR);
+                            reply.push_back(err.to_string().into());
+                            reply
+                        }
+                    };
+
+                    socket.send(reply).await?;
 
                     Ok(())
                 }
@@ -42,7 +64,13 @@ impl<In: DeserializeOwned + 'static, Out: Serialize + 'static> Rep<In, Out> {
             }
         });
 
-        Ok(Self { receive })
+        Ok(Self { receive, encoding })
+    }
+
+    /// Encoding of replies. Requests are decoded by their
+    /// [`Header`](crate::serde::Header).
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.lock() = encoding;
     }
 
     pub fn on_receive(&self, action: impl FnMut(In) -> Out + Send + 'static) {
@@ -59,6 +87,7 @@ mod test {
 
     use crate::{
         scan_for_port,
+        serde::{Encoding, Key},
         zmq::{Rep, Req},
     };
 
@@ -88,4 +117,37 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_rep_decode_error() -> Result<()> {
+        let rep = Rep::<i32, i32>::new("tcp://127.0.0.1:6971").await?;
+        rep.on_receive(|val| val + 1);
+
+        let wrong = Req::<String, i32>::new("tcp://127.0.0.1:6971").await?;
+        let err = wrong.send("one".to_string()).await.unwrap_err();
+        assert!(err.to_string().starts_with("Request failed: Failed to decode i32"));
+
+        let req = Req::<i32, i32>::new("tcp://127.0.0.1:6971").await?;
+        assert_eq!(2, req.send(1).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rep_encrypted() -> Result<()> {
+        let encoding = Encoding::default().encrypt(&Key::generate(6970));
+
+        let rep = Rep::<String, String>::new("tcp://127.0.0.1:6970").await?;
+        rep.set_encoding(encoding);
+        rep.on_receive(|val| val.to_uppercase());
+
+        let req = Req::<String, String>::new("tcp://127.0.0.1:6970").await?;
+        req.set_encoding(encoding);
+
+        for _ in 0..10 {
+            assert_eq!(req.send("secret".to_string()).await?, "SECRET");
+        }
+
+        Ok(())
+    }
 }
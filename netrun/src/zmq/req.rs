@@ -1,15 +1,21 @@
 use std::{marker::PhantomData, sync::Arc};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
+use parking_lot::Mutex as SyncMutex;
 use serde::{Serialize, de::DeserializeOwned};
 use tokio::sync::Mutex;
 use zeromq::{ReqSocket, Socket, SocketRecv, SocketSend};
 
-use crate::serde::{deserialize, serialize};
+use crate::{
+    serde::{Codec, Encoding, MAX_STREAM_SENDERS, ReplayWindows},
+    zmq::ERROR,
+};
 
 pub struct Req<In: Serialize + 'static, Out: DeserializeOwned + 'static> {
-    socket: Mutex<ReqSocket>,
-    _p:     PhantomData<Arc<Mutex<(In, Out)>>>,
+    socket:   Mutex<ReqSocket>,
+    encoding: SyncMutex<Encoding>,
+    replays:  Arc<ReplayWindows>,
+    _p:       PhantomData<Arc<Mutex<(In, Out)>>>,
 }
 
 impl<In: Serialize + 'static, Out: DeserializeOwned + 'static> Req<In, Out> {
@@ -18,18 +24,38 @@ impl<In: Serialize + 'static, Out: DeserializeOwned + 'static> Req<In, Out> {
         socket.connect(endpoint).await?;
 
         Ok(Self {
-            socket: Mutex::new(socket),
-            _p:     PhantomData,
+            socket:   Mutex::new(socket),
+            encoding: SyncMutex::default(),
+            replays:  Arc::new(ReplayWindows::new(MAX_STREAM_SENDERS)),
+            _p:       PhantomData,
         })
     }
 
+    /// Encoding of requests. Replies are decoded by their
+    /// [`Header`](crate::serde::Header).
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.lock() = encoding;
+    }
+
+    /// Replies which can't be decoded fail with a
+    /// [`DecodeError`](crate::serde::DecodeError). So do requests the
+    /// [`Rep`](crate::zmq::Rep) couldn't handle, with its error message.
     pub async fn send(&self, input: In) -> Result<Out> {
         let mut socket = self.socket.lock().await;
 
-        socket.send(serialize(input)?.into()).await?;
+        let encoding = *self.encoding.lock();
+
+        socket.send(encoding.encode(&input)?.into()).await?;
+
+        let reply = socket.recv().await?;
+
+        if reply.len() == 2 && reply.get(0).is_some_and(|frame| frame.as_ref() == ERROR.as_bytes()) {
+            let message = reply.get(1).map(|frame| String::from_utf8_lossy(frame)).unwrap_or_default();
+            bail!("Request failed: {message}");
+        }
 
-        let data: Vec<u8> = socket.recv().await?.try_into().map_err(|err| anyhow!("{err}"))?;
+        let data: Vec<u8> = reply.try_into().map_err(|err| anyhow!("{err}"))?;
 
-        deserialize(&data)
+        self.replays.check(|| encoding.decode(&data))
     }
 }